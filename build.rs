@@ -0,0 +1,18 @@
+use std::path::Path;
+
+/// Expose the resolved wasmtime version to `/version` as `WASMTIME_VERSION`
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let lock = Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    let version = std::fs::read_to_string(lock)
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines();
+            lines.find(|line| *line == "name = \"wasmtime\"")?;
+            let line = lines.next()?;
+            Some(line.strip_prefix("version = \"")?.trim_end_matches('"').to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=WASMTIME_VERSION={}", version);
+}
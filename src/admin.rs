@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+use warp::filters::path::Tail;
+use warp::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use bytes::Bytes;
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+use crate::applet_store::{AppletSettings, AppletStore};
+use crate::auth::{AuthPolicy, Denied};
+use crate::config;
+use crate::config_file::CorsSection;
+use crate::cors;
+use crate::net::Connection;
+use crate::log::{self, Level, TopicFilter};
+use crate::runner::Runner;
+use crate::tls;
+use crate::log_sink::{self, Record};
+
+/// Number of lines returned by the "recent" log route when none is requested
+const DEFAULT_RECENT_LINES: usize = 100;
+
+/// Query parameters accepted by the log routes
+#[derive(Debug, Deserialize)]
+struct LogQuery {
+    topic: Option<String>, // Only include lines logged under this topic
+    lines: Option<usize>,  // How many lines the "recent" route returns
+}
+
+/// Partial update of the log topic filter; omitted fields are left as they are
+#[derive(Debug, Deserialize)]
+struct TopicFilterUpdate {
+    topics: Option<Vec<String>>,
+    level: Option<Level>,
+    levels: Option<BTreeMap<String, Level>>,
+}
+
+/// Query parameters accepted when uploading an applet
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    name: String,                   // Name of the new applet
+    aliases: Option<String>,        // Comma-separated extra names
+    auth: Option<AuthPolicy>,       // Overrides the configured applet auth policy
+    cors_origins: Option<String>,   // Comma-separated origins allowed to call the applet, or `*`
+    cors_methods: Option<String>,   // Comma-separated methods preflights may ask for
+    cors_headers: Option<String>,   // Comma-separated request headers preflights may ask for, or `*`
+    cors_credentials: Option<bool>, // Let browsers send credentials
+    cors_max_age: Option<u64>,      // Seconds browsers may cache a preflight
+}
+
+/// All admin routes, mounted under `/_admin` when the admin API is enabled
+pub fn routes(
+    store: Arc<AppletStore>,
+    runner: Arc<Runner>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path("_admin").and(enabled()).and(
+        access_denied()
+            .or(list_applets(store.clone()))
+            .unify()
+            .or(upload_applet(store.clone()))
+            .unify()
+            .or(delete_applet(store, runner))
+            .unify()
+            .or(log_tail())
+            .unify()
+            .or(log_recent())
+            .unify()
+            .or(get_log_topics())
+            .unify()
+            .or(put_log_topics())
+            .unify(),
+    )
+}
+
+/// Reject every admin request unless the admin API is enabled
+fn enabled() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(|| async {
+            if config::global_config().admin_enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Answer 403 when a client CA is configured and a TLS connection did not
+/// present a certificate signed by it, and 401/403 when admin tokens are
+/// configured and the request's bearer token is missing or lacks the scope
+/// of the route; otherwise pass on to the admin routes
+fn access_denied() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::ext::optional::<Connection>()
+        .and(warp::method())
+        .and(warp::path::tail())
+        .and(warp::header::headers_cloned())
+        .and_then(|connection: Option<Connection>, method: Method, tail: Tail, headers: HeaderMap| async move {
+            if tls::client_cert_required() && connection.is_some_and(|connection| connection.tls && !connection.client_cert) {
+                return Ok(error(StatusCode::FORBIDDEN, "A client certificate is required".to_string()));
+            }
+            match config::global_config().auth.authorize_admin(&headers, required_scope(&method, tail.as_str())) {
+                Ok(_) => Err(warp::reject::not_found()),
+                Err(Denied::Unauthenticated(message)) => {
+                    let mut reply = error(StatusCode::UNAUTHORIZED, message);
+                    reply.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer"));
+                    Ok(reply)
+                }
+                Err(Denied::Forbidden(message)) => Ok(error(StatusCode::FORBIDDEN, message)),
+            }
+        })
+}
+
+/// Scope an admin token needs for a route under `/_admin`
+fn required_scope(method: &Method, path: &str) -> &'static str {
+    let read = method == Method::GET || method == Method::HEAD;
+    match (path.starts_with("applets"), read) {
+        (true, true) => "applets:read",
+        (true, false) => "applets:write",
+        (false, true) => "logs:read",
+        (false, false) => "logs:write",
+    }
+}
+
+/// `GET /_admin/applets`: every stored applet and its metadata
+fn list_applets(store: Arc<AppletStore>) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("applets").and(warp::get()).map(move || {
+        let mut applets = store.list();
+        applets.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        let applets: Vec<_> = applets
+            .into_iter()
+            .map(|(uuid, metadata)| {
+                serde_json::json!({
+                    "uuid": uuid.to_string(),
+                    "name": metadata.name,
+                    "aliases": metadata.settings.aliases,
+                    "auth": metadata.settings.auth.unwrap_or(config::global_config().auth.applet_policy),
+                    "size": metadata.size,
+                    "created_at": metadata.created_at,
+                })
+            })
+            .collect();
+        warp::reply::json(&applets).into_response()
+    })
+}
+
+/// `POST /_admin/applets?name=<name>&aliases=<a,b>&auth=<policy>&cors_origins=<a,b>...`: store the wasm binary
+/// in the body; the `cors_*` parameters override the configured CORS policy for the applet
+fn upload_applet(store: Arc<AppletStore>) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("applets")
+        .and(warp::post())
+        .and(warp::query::<UploadQuery>())
+        .and(warp::body::bytes())
+        .map(move |query: UploadQuery, body: Bytes| {
+            let max_applet_bytes = config::global_config().max_applet_bytes;
+            if max_applet_bytes > 0 && body.len() > max_applet_bytes {
+                return error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Applet is {} bytes, above the {} byte limit", body.len(), max_applet_bytes),
+                );
+            }
+
+            let cors = CorsSection {
+                origins: query.cors_origins.as_deref().map(split_list),
+                methods: query.cors_methods.as_deref().map(split_list),
+                headers: query.cors_headers.as_deref().map(split_list),
+                credentials: query.cors_credentials,
+                max_age: query.cors_max_age,
+            };
+            if let Err(e) = cors::validate(&cors) {
+                return error(StatusCode::BAD_REQUEST, e.to_string());
+            }
+            let settings = AppletSettings {
+                aliases: query.aliases.as_deref().map(split_list).unwrap_or_default(),
+                auth: query.auth,
+                cors,
+                ..AppletSettings::default()
+            };
+            match store.create_with(body.to_vec(), query.name.clone(), settings) {
+                Ok(uuid) => {
+                    log::log("substrate", &format!("Applet '{}' uploaded with UUID: {}", query.name, uuid));
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "uuid": uuid.to_string() })),
+                        StatusCode::CREATED,
+                    )
+                    .into_response()
+                }
+                Err(e) => error(StatusCode::CONFLICT, e.to_string()),
+            }
+        })
+}
+
+/// `DELETE /_admin/applets/<uuid|name|alias>`: remove an applet
+fn delete_applet(
+    store: Arc<AppletStore>,
+    runner: Arc<Runner>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("applets" / String)
+        .and(warp::delete())
+        .map(move |id: String| {
+            let Some(uuid) = store.resolve(&id) else {
+                return error(StatusCode::NOT_FOUND, format!("Applet not found: {}", id));
+            };
+            store.delete(&uuid);
+            runner.evict(&uuid);
+            log::log("substrate", &format!("Applet {} deleted", uuid));
+            StatusCode::NO_CONTENT.into_response()
+        })
+}
+
+/// The non-empty items of a comma-separated query parameter
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+/// JSON error body with the given status
+fn error(status: StatusCode, message: String) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status).into_response()
+}
+
+/// `GET /_admin/log-topics`: the topic filter currently in effect
+fn get_log_topics() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("log-topics")
+        .and(warp::get())
+        .map(|| warp::reply::json(&log::topic_filter()).into_response())
+}
+
+/// `PUT /_admin/log-topics`: change enabled topics and levels at runtime.
+/// With `--log-topics-file` set, the next SIGHUP replaces these changes
+/// with the configured topics and the file's.
+fn put_log_topics() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("log-topics")
+        .and(warp::put())
+        .and(warp::body::json::<TopicFilterUpdate>())
+        .map(|update: TopicFilterUpdate| {
+            let mut filter: TopicFilter = log::topic_filter();
+            if let Some(topics) = update.topics {
+                filter.topics = topics.into_iter().collect();
+            }
+            if let Some(level) = update.level {
+                filter.level = level;
+            }
+            if let Some(levels) = update.levels {
+                filter.levels = levels;
+            }
+            log::set_topic_filter(filter.clone());
+            log::log("substrate", "Log topics updated through the admin API");
+            warp::reply::json(&filter).into_response()
+        })
+}
+
+/// `GET /_admin/logs/<uuid>?topic=<topic>`: stream an applet's log lines as Server-Sent Events
+fn log_tail() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("logs" / Uuid)
+        .and(warp::get())
+        .and(warp::query::<LogQuery>())
+        .map(|uuid: Uuid, query: LogQuery| {
+            let events = tail_events(uuid, query.topic);
+            warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+        })
+}
+
+/// `GET /_admin/logs/<uuid>/recent?lines=<n>&topic=<topic>`: the last lines an applet logged
+fn log_recent() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path!("logs" / Uuid / "recent")
+        .and(warp::get())
+        .and(warp::query::<LogQuery>())
+        .map(|uuid: Uuid, query: LogQuery| {
+            let Some(buffer) = log_sink::memory_buffer() else {
+                return warp::reply::with_status(
+                    "The memory log sink is not enabled\n".to_string(),
+                    StatusCode::NOT_FOUND,
+                )
+                .into_response();
+            };
+
+            let count = query.lines.unwrap_or(DEFAULT_RECENT_LINES);
+            let topic = query.topic;
+            let mut body = String::new();
+            for record in buffer.recent(count, |record| matches(record, uuid, topic.as_deref())) {
+                body.push_str(&record.to_text());
+                body.push('\n');
+            }
+            body.into_response()
+        })
+}
+
+/// Turn the log broadcast channel into SSE events for one applet
+fn tail_events(
+    uuid: Uuid,
+    topic: Option<String>,
+) -> impl Stream<Item = Result<Event, Infallible>> + Send + 'static {
+    stream::unfold((log::subscribe(), topic), move |(mut rx, topic)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(record) if matches(&record, uuid, topic.as_deref()) => {
+                    let event = Event::default()
+                        .event(record.level.as_str().to_ascii_lowercase())
+                        .data(record.to_text());
+                    return Some((Ok(event), (rx, topic)));
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    // The subscriber fell behind; tell it how many lines were dropped
+                    let event = Event::default().event("lagged").data(skipped.to_string());
+                    return Some((Ok(event), (rx, topic)));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Whether a record was logged by `uuid` (and under `topic`, if given)
+fn matches(record: &Record, uuid: Uuid, topic: Option<&str>) -> bool {
+    record.context.as_ref().is_some_and(|ctx| ctx.applet == uuid)
+        && topic.is_none_or(|topic| record.topic == topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_routes_need_the_scope_of_their_area() {
+        assert_eq!(required_scope(&Method::GET, "applets"), "applets:read");
+        assert_eq!(required_scope(&Method::HEAD, "applets/echo"), "applets:read");
+        assert_eq!(required_scope(&Method::POST, "applets"), "applets:write");
+        assert_eq!(required_scope(&Method::DELETE, "applets/echo"), "applets:write");
+        assert_eq!(required_scope(&Method::GET, "logs/recent"), "logs:read");
+        assert_eq!(required_scope(&Method::GET, "log-topics"), "logs:read");
+        assert_eq!(required_scope(&Method::PUT, "log-topics"), "logs:write");
+    }
+}
@@ -1,47 +1,193 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::auth::AuthPolicy;
+use crate::config::RateLimitKey;
+use crate::config_file::{CorsSection, LimitsSection};
+
+/// Paths served by the host itself, which applets cannot be named after
+const RESERVED_NAMES: [&str; 6] = ["_admin", "_events", "metrics", "healthz", "readyz", "version"];
+
+/// Whether `name` can name an applet. Names end up in URL paths and
+/// recording file names, so they are kept to letters, digits, `-`, `_`
+/// and `.`, and cannot start with a dot.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// WASI environment given to an applet's guest
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasiSettings {
+    pub args: Vec<String>,               // Command-line arguments seen by the guest
+    pub env: BTreeMap<String, String>,   // Environment variables seen by the guest
+    pub dirs: Vec<PreopenDir>,           // Host directories made visible to the guest
+    pub inherit_stdout: bool,            // Let the guest write to the server's stdout
+    pub inherit_stderr: bool,            // Let the guest write to the server's stderr
+}
+
+/// A host directory mapped into the guest's filesystem
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreopenDir {
+    pub host: PathBuf,  // Directory on the host
+    pub guest: String,  // Path the guest sees it under
+}
+
+/// Deployment settings for an applet
+#[derive(Clone, Debug, Default)]
+pub struct AppletSettings {
+    pub aliases: Vec<String>,              // Extra names the applet can be reached by
+    pub limits: LimitsSection,             // Overrides of the configured limits
+    pub capabilities: Option<Vec<String>>, // Host functions it may import (all when unset)
+    pub wasi: WasiSettings,                // WASI environment
+    pub deterministic: bool,               // Run every invocation from a seeded virtual source
+    pub allow_seed_header: bool,           // Requests may pick the seed with `x-substrate-seed`
+    pub pinned: bool,                      // Compiled at startup; readiness waits for it
+    pub auth: Option<AuthPolicy>,          // Overrides the configured applet auth policy
+    pub cors: CorsSection,                 // Overrides of the configured CORS policy
+    pub stream_body: bool,                 // Guest reads the body as it arrives instead of buffered
+    pub websocket: bool,                   // Upgrade requests are served as WebSocket connections
+}
+
 /// Metadata associated with each applet
 #[derive(Clone, Debug)]
 pub struct AppletMetadata {
     pub name: String,    // Name of the applet
     pub size: usize,     // Size of the wasm file in bytes
     pub created_at: u64, // Timestamp when the applet was stored
+    pub settings: AppletSettings, // How the applet is deployed
+}
+
+/// A stored wasm binary together with its metadata
+type AppletEntry = (Vec<u8>, AppletMetadata);
+
+#[derive(Default)]
+struct Applets {
+    entries: HashMap<Uuid, AppletEntry>, // Map UUID to (wasm binary, metadata)
+    names: HashMap<String, Uuid>,        // Names and aliases to UUID
 }
 
 /// AppletStore to manage storage and retrieval of wasm files
 #[derive(Clone)]
 pub struct AppletStore {
-    store: Arc<Mutex<HashMap<Uuid, (Vec<u8>, AppletMetadata)>>>, // Map UUID to (wasm binary, metadata)
+    store: Arc<Mutex<Applets>>,
 }
 
 impl AppletStore {
     /// Create a new AppletStore
     pub fn new() -> Self {
         AppletStore {
-            store: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(Mutex::new(Applets::default())),
         }
     }
 
-    /// Store a new applet, returning its UUID
-    pub fn create(&self, wasm_binary: Vec<u8>, name: String) -> Uuid {
+    /// Store a new applet, returning its UUID. Fails when the name or one
+    /// of the aliases is already taken, or the settings are invalid.
+    pub fn create_with(&self, wasm_binary: Vec<u8>, name: String, settings: AppletSettings) -> Result<Uuid> {
+        let mut store = self.store.lock().unwrap();
+
+        let mut names = vec![name.clone()];
+        names.extend(settings.aliases.iter().cloned());
+        for (i, candidate) in names.iter().enumerate() {
+            if !valid_name(candidate) {
+                return Err(anyhow!(
+                    "Invalid applet name or alias '{}': use letters, digits, '-', '_' and '.', not starting with '.'",
+                    candidate
+                ));
+            }
+            if store.names.contains_key(candidate) || names[..i].contains(candidate) {
+                return Err(anyhow!("Applet name or alias '{}' is already in use", candidate));
+            }
+            if RESERVED_NAMES.contains(&candidate.as_str()) {
+                return Err(anyhow!("Applet name or alias '{}' is reserved", candidate));
+            }
+            if Uuid::parse_str(candidate).is_ok() {
+                return Err(anyhow!("Applet name or alias '{}' must not be a UUID", candidate));
+            }
+        }
+        // Limits are resolved per request, where an invalid key could only be ignored
+        if let Some(key) = &settings.limits.rate_limit_key {
+            RateLimitKey::parse(key)?;
+        }
+
         let uuid = Uuid::new_v4();
         let metadata = AppletMetadata {
             name,
             size: wasm_binary.len(),
             created_at: Self::current_timestamp(),
+            settings,
         };
 
+        for candidate in names {
+            store.names.insert(candidate, uuid);
+        }
+        store.entries.insert(uuid, (wasm_binary, metadata));
+        Ok(uuid)
+    }
+
+    /// Swap in a new wasm binary for an existing applet, keeping its name
+    /// and settings. Returns whether the applet exists.
+    pub fn replace(&self, uuid: &Uuid, wasm_binary: Vec<u8>) -> bool {
         let mut store = self.store.lock().unwrap();
-        store.insert(uuid, (wasm_binary, metadata));
-        uuid
+        let Some(entry) = store.entries.get_mut(uuid) else {
+            return false;
+        };
+        entry.1.size = wasm_binary.len();
+        entry.1.created_at = Self::current_timestamp();
+        entry.0 = wasm_binary;
+        true
     }
 
     /// Retrieve a wasm binary and metadata by UUID
-    pub fn get(&self, uuid: &Uuid) -> Option<(Vec<u8>, AppletMetadata)> {
+    pub fn get(&self, uuid: &Uuid) -> Option<AppletEntry> {
+        let store = self.store.lock().unwrap();
+        store.entries.get(uuid).cloned()
+    }
+
+    /// Retrieve only the metadata of an applet by UUID
+    pub fn metadata(&self, uuid: &Uuid) -> Option<AppletMetadata> {
+        let store = self.store.lock().unwrap();
+        store.entries.get(uuid).map(|(_, metadata)| metadata.clone())
+    }
+
+    /// Find an applet by UUID, name or alias
+    pub fn resolve(&self, id: &str) -> Option<Uuid> {
+        let store = self.store.lock().unwrap();
+        match Uuid::parse_str(id) {
+            Ok(uuid) => store.entries.contains_key(&uuid).then_some(uuid),
+            Err(_) => store.names.get(id).copied(),
+        }
+    }
+
+    /// Metadata of every stored applet
+    pub fn list(&self) -> Vec<(Uuid, AppletMetadata)> {
+        let store = self.store.lock().unwrap();
+        store
+            .entries
+            .iter()
+            .map(|(uuid, (_, metadata))| (*uuid, metadata.clone()))
+            .collect()
+    }
+
+    /// Remove an applet and its names, returning whether it existed
+    pub fn delete(&self, uuid: &Uuid) -> bool {
+        let mut store = self.store.lock().unwrap();
+        store.names.retain(|_, target| target != uuid);
+        store.entries.remove(uuid).is_some()
+    }
+
+    /// Number of stored applets and their combined size in bytes
+    pub fn usage(&self) -> (usize, usize) {
         let store = self.store.lock().unwrap();
-        store.get(uuid).cloned()
+        let bytes = store.entries.values().map(|(_, metadata)| metadata.size).sum();
+        (store.entries.len(), bytes)
     }
 
     /// Helper function to get the current timestamp (UNIX epoch)
@@ -51,3 +197,33 @@ impl AppletStore {
         now.as_secs()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_names_unfit_for_paths() {
+        let store = AppletStore::new();
+        for name in ["../../x", "a/b", ".hidden", "", "with space", "_admin", "6f1c3e5a-53a2-4c53-9d1b-0c0d8e6b9b1e"] {
+            assert!(store.create_with(Vec::new(), name.to_string(), AppletSettings::default()).is_err(), "{}", name);
+        }
+
+        let uuid = store.create_with(Vec::new(), "echo-2.v1_b".to_string(), AppletSettings::default()).unwrap();
+        assert_eq!(store.resolve("echo-2.v1_b"), Some(uuid));
+
+        let settings = AppletSettings { aliases: vec!["../alias".to_string()], ..Default::default() };
+        assert!(store.create_with(Vec::new(), "other".to_string(), settings).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_rate_limit_keys() {
+        let store = AppletStore::new();
+        let limits = |key: &str| AppletSettings {
+            limits: LimitsSection { rate_limit_key: Some(key.to_string()), ..Default::default() },
+            ..Default::default()
+        };
+        assert!(store.create_with(Vec::new(), "bad".to_string(), limits("header:")).is_err());
+        assert!(store.create_with(Vec::new(), "tenant".to_string(), limits("header:x-tenant")).is_ok());
+    }
+}
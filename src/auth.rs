@@ -0,0 +1,664 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64URL};
+use base64::Engine as _;
+use ring::{hmac, signature};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::http::{HeaderMap, Method};
+
+use crate::config_file::{AdminTokenSection, AuthSection, JwtKeySection};
+
+/// Header carrying the key for the `api_key` policy
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Headers of an HMAC-signed request: the key ID, the Unix time it was
+/// signed at, and the hex HMAC-SHA256 of `"<timestamp>\n<METHOD>\n<path?query>\n"`
+/// followed by the body. Each signature is accepted once.
+pub const KEY_ID_HEADER: &str = "x-substrate-key-id";
+pub const TIMESTAMP_HEADER: &str = "x-substrate-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-substrate-signature";
+
+/// Headers that carry credentials, kept out of recordings
+pub const CREDENTIAL_HEADERS: [&str; 6] =
+    ["authorization", "proxy-authorization", "cookie", "set-cookie", API_KEY_HEADER, SIGNATURE_HEADER];
+
+/// How far a signed request's timestamp may be from the server's clock
+const MAX_SIGNATURE_AGE_SECS: i64 = 300;
+
+/// Clock skew tolerated when checking JWT `exp` and `nbf`
+const JWT_LEEWAY_SECS: f64 = 60.0;
+
+/// Placeholder for secrets in `config check` output
+const REDACTED: &str = "<redacted>";
+
+/// Scopes an admin token can be granted
+pub const ADMIN_SCOPES: [&str; 4] = ["applets:read", "applets:write", "logs:read", "logs:write"];
+
+/// How callers of an applet must authenticate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthPolicy {
+    #[default]
+    None,
+    ApiKey,
+    Hmac,
+    Jwt,
+}
+
+impl AuthPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(AuthPolicy::None),
+            "api_key" => Some(AuthPolicy::ApiKey),
+            "hmac" => Some(AuthPolicy::Hmac),
+            "jwt" => Some(AuthPolicy::Jwt),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthPolicy::None => "none",
+            AuthPolicy::ApiKey => "api_key",
+            AuthPolicy::Hmac => "hmac",
+            AuthPolicy::Jwt => "jwt",
+        }
+    }
+}
+
+/// A verified caller, handed to the guest through the `principal` host call
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Principal {
+    pub id: String,         // API key name, HMAC key ID or JWT subject
+    pub scheme: AuthPolicy, // How the caller was verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claims: Option<Value>, // JWT claims
+}
+
+/// A bearer token for the admin API
+#[derive(Clone, Debug)]
+pub struct AdminToken {
+    pub name: String,        // Shown in logs instead of the token
+    pub token: String,
+    pub scopes: Vec<String>, // Entries of `ADMIN_SCOPES`
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256,
+    EdDSA,
+}
+
+impl JwtAlgorithm {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "HS256" => Some(JwtAlgorithm::HS256),
+            "RS256" => Some(JwtAlgorithm::RS256),
+            "ES256" => Some(JwtAlgorithm::ES256),
+            "EdDSA" => Some(JwtAlgorithm::EdDSA),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            JwtAlgorithm::HS256 => "HS256",
+            JwtAlgorithm::RS256 => "RS256",
+            JwtAlgorithm::ES256 => "ES256",
+            JwtAlgorithm::EdDSA => "EdDSA",
+        }
+    }
+}
+
+/// A key JWTs are verified with
+#[derive(Clone, Debug)]
+pub struct JwtKey {
+    kid: Option<String>,             // Matched against the token's `kid` when both are set
+    algorithm: JwtAlgorithm,
+    key: Vec<u8>,                    // HMAC secret, or the public key from the SPKI
+    public_key_file: Option<String>, // Where a public key was read from
+}
+
+/// Authentication settings for the admin API and applets
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    pub applet_policy: AuthPolicy,        // Policy of applets that do not set one
+    pub admin_tokens: Vec<AdminToken>,    // Admin API is open when empty
+    pub api_keys: BTreeMap<String, String>, // Principal name to key
+    pub hmac_keys: BTreeMap<String, String>, // Key ID to secret
+    pub jwt_keys: Vec<JwtKey>,
+    pub jwt_issuer: Option<String>,       // Required `iss`, when set
+    pub jwt_audience: Option<String>,     // Required `aud`, when set
+}
+
+impl AuthConfig {
+    pub fn resolve(section: AuthSection) -> Result<Self> {
+        let applet_policy = match section.applet_policy {
+            Some(value) => AuthPolicy::parse(&value)
+                .ok_or_else(|| anyhow!("Invalid applet auth policy '{}': expected none, api_key, hmac or jwt", value))?,
+            None => AuthPolicy::None,
+        };
+
+        let admin_tokens = section
+            .admin_tokens
+            .unwrap_or_default()
+            .into_iter()
+            .map(|token| {
+                if let Some(scope) = token.scopes.iter().find(|scope| !ADMIN_SCOPES.contains(&scope.as_str())) {
+                    return Err(anyhow!("Admin token '{}' has unknown scope '{}'", token.name, scope));
+                }
+                if token.token.is_empty() {
+                    return Err(anyhow!("Admin token '{}' is empty", token.name));
+                }
+                Ok(AdminToken { name: token.name, token: token.token, scopes: token.scopes })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let jwt_keys = section
+            .jwt_keys
+            .unwrap_or_default()
+            .into_iter()
+            .map(JwtKey::resolve)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(AuthConfig {
+            applet_policy,
+            admin_tokens,
+            api_keys: section.api_keys.unwrap_or_default(),
+            hmac_keys: section.hmac_keys.unwrap_or_default(),
+            jwt_keys,
+            jwt_issuer: section.jwt_issuer,
+            jwt_audience: section.jwt_audience,
+        })
+    }
+
+    /// The settings as a configuration file section, with secrets redacted
+    pub fn to_section(&self) -> AuthSection {
+        let redacted = |map: &BTreeMap<String, String>| {
+            map.keys().map(|key| (key.clone(), REDACTED.to_string())).collect()
+        };
+        AuthSection {
+            applet_policy: Some(self.applet_policy.as_str().to_string()),
+            jwt_issuer: self.jwt_issuer.clone(),
+            jwt_audience: self.jwt_audience.clone(),
+            api_keys: Some(redacted(&self.api_keys)),
+            hmac_keys: Some(redacted(&self.hmac_keys)),
+            // Empty lists are left out: TOML cannot write them after the key tables
+            admin_tokens: (!self.admin_tokens.is_empty()).then(|| {
+                self.admin_tokens
+                    .iter()
+                    .map(|token| AdminTokenSection {
+                        name: token.name.clone(),
+                        token: REDACTED.to_string(),
+                        scopes: token.scopes.clone(),
+                    })
+                    .collect()
+            }),
+            jwt_keys: (!self.jwt_keys.is_empty()).then(|| {
+                self.jwt_keys
+                    .iter()
+                    .map(|key| JwtKeySection {
+                        kid: key.kid.clone(),
+                        algorithm: key.algorithm.as_str().to_string(),
+                        secret: key.public_key_file.is_none().then(|| REDACTED.to_string()),
+                        public_key_file: key.public_key_file.clone(),
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    /// Check an admin request's bearer token against the configured tokens
+    pub fn authorize_admin(&self, headers: &HeaderMap, scope: &str) -> std::result::Result<(), Denied> {
+        if self.admin_tokens.is_empty() {
+            return Ok(());
+        }
+        let token = bearer_token(headers).ok_or_else(|| Denied::Unauthenticated("A bearer token is required".into()))?;
+        let token = self
+            .admin_tokens
+            .iter()
+            .find(|candidate| constant_time_eq(candidate.token.as_bytes(), token.as_bytes()))
+            .ok_or_else(|| Denied::Unauthenticated("Invalid bearer token".into()))?;
+        if !token.scopes.iter().any(|granted| granted == scope) {
+            return Err(Denied::Forbidden(format!("Token '{}' lacks the '{}' scope", token.name, scope)));
+        }
+        Ok(())
+    }
+
+    /// Verify a request to an applet under `policy`. Returns the verified
+    /// caller (none for the `none` policy) or why the request was refused.
+    pub fn authenticate(
+        &self,
+        policy: AuthPolicy,
+        method: &Method,
+        path_and_query: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<Principal>> {
+        let principal = match policy {
+            AuthPolicy::None => return Ok(None),
+            AuthPolicy::ApiKey => self.verify_api_key(headers)?,
+            AuthPolicy::Hmac => self.verify_hmac(method, path_and_query, headers, body)?,
+            AuthPolicy::Jwt => self.verify_jwt(headers)?,
+        };
+        Ok(Some(principal))
+    }
+
+    fn verify_api_key(&self, headers: &HeaderMap) -> Result<Principal> {
+        if header(headers, API_KEY_HEADER).is_none() {
+            return Err(anyhow!("The {} header is required", API_KEY_HEADER));
+        }
+        let name = self.api_key_name(headers).ok_or_else(|| anyhow!("Invalid API key"))?;
+        Ok(Principal { id: name, scheme: AuthPolicy::ApiKey, claims: None })
+    }
+
+    /// Name of the configured API key the request carries, if it carries one
+    pub fn api_key_name(&self, headers: &HeaderMap) -> Option<String> {
+        let key = header(headers, API_KEY_HEADER)?;
+        self.api_keys
+            .iter()
+            .find(|(_, candidate)| constant_time_eq(candidate.as_bytes(), key.as_bytes()))
+            .map(|(name, _)| name.clone())
+    }
+
+    fn verify_hmac(&self, method: &Method, path_and_query: &str, headers: &HeaderMap, body: &[u8]) -> Result<Principal> {
+        let (Some(key_id), Some(timestamp), Some(signature)) = (
+            header(headers, KEY_ID_HEADER),
+            header(headers, TIMESTAMP_HEADER),
+            header(headers, SIGNATURE_HEADER),
+        ) else {
+            return Err(anyhow!(
+                "Signed requests need the {}, {} and {} headers",
+                KEY_ID_HEADER,
+                TIMESTAMP_HEADER,
+                SIGNATURE_HEADER
+            ));
+        };
+        let secret = self.hmac_keys.get(key_id).ok_or_else(|| anyhow!("Unknown key ID '{}'", key_id))?;
+        let signed_at: i64 = timestamp.parse().map_err(|_| anyhow!("Invalid {} header", TIMESTAMP_HEADER))?;
+        let now = chrono::Utc::now().timestamp();
+        if (now - signed_at).abs() > MAX_SIGNATURE_AGE_SECS {
+            return Err(anyhow!("Request signature has expired"));
+        }
+        let signature = decode_hex(signature).ok_or_else(|| anyhow!("Invalid {} header", SIGNATURE_HEADER))?;
+
+        let mut message = format!("{}\n{}\n{}\n", timestamp, method, path_and_query).into_bytes();
+        message.extend_from_slice(body);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        hmac::verify(&key, &message, &signature).map_err(|_| anyhow!("Invalid request signature"))?;
+        if !first_use(key_id, signature, signed_at, now) {
+            return Err(anyhow!("Request signature has already been used"));
+        }
+        Ok(Principal { id: key_id.to_string(), scheme: AuthPolicy::Hmac, claims: None })
+    }
+
+    fn verify_jwt(&self, headers: &HeaderMap) -> Result<Principal> {
+        let token = bearer_token(headers).ok_or_else(|| anyhow!("A bearer token is required"))?;
+        let mut parts = token.split('.');
+        let (Some(header_part), Some(claims_part), Some(signature_part), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow!("Malformed token"));
+        };
+        let decode = |part: &str| BASE64URL.decode(part).map_err(|_| anyhow!("Malformed token"));
+        let jwt_header: Value = serde_json::from_slice(&decode(header_part)?).map_err(|_| anyhow!("Malformed token"))?;
+        let claims: Value = serde_json::from_slice(&decode(claims_part)?).map_err(|_| anyhow!("Malformed token"))?;
+        let signature = decode(signature_part)?;
+
+        // The algorithm comes from the key, never from the token alone
+        let algorithm = jwt_header["alg"]
+            .as_str()
+            .and_then(JwtAlgorithm::parse)
+            .ok_or_else(|| anyhow!("Unsupported token algorithm"))?;
+        let kid = jwt_header["kid"].as_str();
+        let message = format!("{}.{}", header_part, claims_part);
+        let verified = self
+            .jwt_keys
+            .iter()
+            .filter(|key| key.algorithm == algorithm)
+            .filter(|key| match (&key.kid, kid) {
+                (Some(expected), Some(kid)) => expected == kid,
+                _ => true,
+            })
+            .any(|key| key.verify(message.as_bytes(), &signature));
+        if !verified {
+            return Err(anyhow!("Invalid token signature"));
+        }
+
+        // Tokens that never expire cannot be revoked, so `exp` is required
+        let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+        let exp = numeric_date(&claims, "exp")?.ok_or_else(|| anyhow!("Token has no expiry"))?;
+        if now > exp + JWT_LEEWAY_SECS {
+            return Err(anyhow!("Token has expired"));
+        }
+        if numeric_date(&claims, "nbf")?.is_some_and(|nbf| now + JWT_LEEWAY_SECS < nbf) {
+            return Err(anyhow!("Token is not valid yet"));
+        }
+        if let Some(issuer) = &self.jwt_issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err(anyhow!("Token has the wrong issuer"));
+            }
+        }
+        if let Some(audience) = &self.jwt_audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !matches {
+                return Err(anyhow!("Token has the wrong audience"));
+            }
+        }
+        let subject = claims["sub"].as_str().ok_or_else(|| anyhow!("Token has no subject"))?.to_string();
+        Ok(Principal { id: subject, scheme: AuthPolicy::Jwt, claims: Some(claims) })
+    }
+}
+
+/// A JWT NumericDate claim: seconds since the epoch, possibly fractional.
+/// `None` when the claim is absent; an error when it is not a number.
+fn numeric_date(claims: &Value, name: &str) -> Result<Option<f64>> {
+    match claims.get(name) {
+        None => Ok(None),
+        Some(value) => value.as_f64().map(Some).ok_or_else(|| anyhow!("Token has an invalid `{}` claim", name)),
+    }
+}
+
+/// Key ID and signature of a signed request
+type SignatureKey = (String, Vec<u8>);
+
+/// Signed requests accepted while their timestamps are in the window
+#[derive(Default)]
+struct SeenSignatures {
+    keys: HashSet<SignatureKey>,
+    expiring: BinaryHeap<Reverse<(i64, SignatureKey)>>, // By the time they leave the window, soonest first
+}
+
+impl SeenSignatures {
+    /// Remember a signature; false when it was seen before. Signatures that
+    /// have left the window are dropped first.
+    fn first_use(&mut self, key: SignatureKey, signed_at: i64, now: i64) -> bool {
+        while self.expiring.peek().is_some_and(|Reverse((expires, _))| *expires < now) {
+            if let Some(Reverse((_, expired))) = self.expiring.pop() {
+                self.keys.remove(&expired);
+            }
+        }
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.expiring.push(Reverse((signed_at + MAX_SIGNATURE_AGE_SECS, key)));
+        true
+    }
+}
+
+static SEEN_SIGNATURES: OnceLock<Mutex<SeenSignatures>> = OnceLock::new();
+
+/// Remember a verified signature; false when it was seen before, so a
+/// captured request cannot be replayed while its timestamp is still valid
+fn first_use(key_id: &str, signature: Vec<u8>, signed_at: i64, now: i64) -> bool {
+    let mut seen = SEEN_SIGNATURES.get_or_init(Default::default).lock().unwrap();
+    seen.first_use((key_id.to_string(), signature), signed_at, now)
+}
+
+/// Why an admin request was refused
+pub enum Denied {
+    Unauthenticated(String), // 401: no token, or an unknown one
+    Forbidden(String),       // 403: the token lacks the scope
+}
+
+impl JwtKey {
+    fn resolve(section: JwtKeySection) -> Result<Self> {
+        let algorithm = JwtAlgorithm::parse(&section.algorithm).ok_or_else(|| {
+            anyhow!("Unsupported JWT algorithm '{}': expected HS256, RS256, ES256 or EdDSA", section.algorithm)
+        })?;
+        let key = match (algorithm, &section.secret, &section.public_key_file) {
+            (JwtAlgorithm::HS256, Some(secret), None) => secret.as_bytes().to_vec(),
+            (JwtAlgorithm::HS256, _, _) => return Err(anyhow!("HS256 JWT keys need a `secret` and no `public_key_file`")),
+            (_, None, Some(path)) => read_public_key(Path::new(path))?,
+            (_, _, _) => {
+                return Err(anyhow!("{} JWT keys need a `public_key_file` and no `secret`", algorithm.as_str()))
+            }
+        };
+        Ok(JwtKey { kid: section.kid, algorithm, key, public_key_file: section.public_key_file })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let public_key = |algorithm: &'static dyn signature::VerificationAlgorithm| {
+            signature::UnparsedPublicKey::new(algorithm, &self.key).verify(message, signature).is_ok()
+        };
+        match self.algorithm {
+            JwtAlgorithm::HS256 => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, &self.key);
+                hmac::verify(&key, message, signature).is_ok()
+            }
+            JwtAlgorithm::RS256 => public_key(&signature::RSA_PKCS1_2048_8192_SHA256),
+            JwtAlgorithm::ES256 => public_key(&signature::ECDSA_P256_SHA256_FIXED),
+            JwtAlgorithm::EdDSA => public_key(&signature::ED25519),
+        }
+    }
+}
+
+/// Read a PEM `PUBLIC KEY` (SubjectPublicKeyInfo) and return the key bits
+/// in the form ring expects
+fn read_public_key(path: &Path) -> Result<Vec<u8>> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Cannot read '{}'", path.display()))?;
+    let base64: String = contents
+        .lines()
+        .skip_while(|line| line.trim() != "-----BEGIN PUBLIC KEY-----")
+        .skip(1)
+        .take_while(|line| line.trim() != "-----END PUBLIC KEY-----")
+        .map(str::trim)
+        .collect();
+    let der = BASE64
+        .decode(base64)
+        .ok()
+        .filter(|der| !der.is_empty())
+        .ok_or_else(|| anyhow!("No PEM public key in '{}'", path.display()))?;
+    spki_key_bits(&der).ok_or_else(|| anyhow!("Invalid public key in '{}'", path.display()))
+}
+
+/// The subject public key of a DER SubjectPublicKeyInfo:
+/// `SEQUENCE { SEQUENCE algorithm, BIT STRING key }`
+fn spki_key_bits(der: &[u8]) -> Option<Vec<u8>> {
+    let (tag, info, _) = der_element(der)?;
+    if tag != 0x30 {
+        return None;
+    }
+    let (_, _, rest) = der_element(info)?;
+    let (tag, bits, _) = der_element(rest)?;
+    match bits.split_first() {
+        Some((0, key)) if tag == 0x03 => Some(key.to_vec()),
+        _ => None,
+    }
+}
+
+/// Split one DER element off `input`: its tag, contents and what follows
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim)
+}
+
+/// The token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = header(headers, "authorization")?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Compare secrets without returning early on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    const SECRET: &str = "s3cret";
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            hmac_keys: BTreeMap::from([("ci".to_string(), SECRET.to_string())]),
+            jwt_keys: vec![JwtKey {
+                kid: Some("one".to_string()),
+                algorithm: JwtAlgorithm::HS256,
+                key: SECRET.as_bytes().to_vec(),
+                public_key_file: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn signed(timestamp: i64, body: &[u8]) -> HeaderMap {
+        let mut message = format!("{}\nPOST\n/echo?x=1\n", timestamp).into_bytes();
+        message.extend_from_slice(body);
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes()), &message);
+        let signature: String = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+        headers(&[
+            (KEY_ID_HEADER, "ci".to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (SIGNATURE_HEADER, signature),
+        ])
+    }
+
+    fn jwt(header: Value, claims: Value) -> HeaderMap {
+        let encode = |value: &Value| BASE64URL.encode(serde_json::to_vec(value).unwrap());
+        let message = format!("{}.{}", encode(&header), encode(&claims));
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes()), message.as_bytes());
+        let token = format!("{}.{}", message, BASE64URL.encode(tag.as_ref()));
+        headers(&[("authorization", format!("Bearer {}", token))])
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    #[test]
+    fn hmac_accepts_each_signature_once() {
+        let config = config();
+        let headers = signed(now(), b"hello");
+        let principal = config.verify_hmac(&Method::POST, "/echo?x=1", &headers, b"hello").unwrap();
+        assert_eq!(principal.id, "ci");
+
+        let error = config.verify_hmac(&Method::POST, "/echo?x=1", &headers, b"hello").unwrap_err();
+        assert_eq!(error.to_string(), "Request signature has already been used");
+    }
+
+    #[test]
+    fn hmac_rejects_tampering_and_stale_timestamps() {
+        let config = config();
+        let headers = signed(now() - 1, b"hello");
+        assert!(config.verify_hmac(&Method::POST, "/echo?x=1", &headers, b"hellO").is_err());
+        assert!(config.verify_hmac(&Method::GET, "/echo?x=1", &headers, b"hello").is_err());
+        assert!(config.verify_hmac(&Method::POST, "/echo?x=2", &headers, b"hello").is_err());
+
+        let stale = signed(now() - MAX_SIGNATURE_AGE_SECS - 10, b"");
+        let error = config.verify_hmac(&Method::POST, "/echo?x=1", &stale, b"").unwrap_err();
+        assert_eq!(error.to_string(), "Request signature has expired");
+
+        let mut unknown = signed(now(), b"");
+        unknown.insert(KEY_ID_HEADER, HeaderValue::from_static("other"));
+        assert!(config.verify_hmac(&Method::POST, "/echo?x=1", &unknown, b"").is_err());
+    }
+
+    #[test]
+    fn jwt_accepts_a_valid_token() {
+        let headers = jwt(
+            serde_json::json!({ "alg": "HS256", "kid": "one" }),
+            serde_json::json!({ "sub": "alice", "exp": now() + 60 }),
+        );
+        let principal = config().verify_jwt(&headers).unwrap();
+        assert_eq!(principal.id, "alice");
+        assert_eq!(principal.claims.unwrap()["sub"], "alice");
+    }
+
+    #[test]
+    fn jwt_rejects_wrong_algorithm_or_key_id() {
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 60 });
+        for header in [
+            serde_json::json!({ "alg": "none", "kid": "one" }),
+            serde_json::json!({ "alg": "RS256", "kid": "one" }),
+            serde_json::json!({ "alg": "HS256", "kid": "two" }),
+        ] {
+            assert!(config().verify_jwt(&jwt(header.clone(), claims.clone())).is_err(), "{}", header);
+        }
+    }
+
+    #[test]
+    fn jwt_checks_expiry_and_not_before() {
+        let header = serde_json::json!({ "alg": "HS256" });
+        let verify = |claims: Value| config().verify_jwt(&jwt(header.clone(), claims)).map_err(|e| e.to_string());
+
+        let expired = verify(serde_json::json!({ "sub": "alice", "exp": now() - JWT_LEEWAY_SECS as i64 - 10 }));
+        assert_eq!(expired.unwrap_err(), "Token has expired");
+        assert_eq!(verify(serde_json::json!({ "sub": "alice" })).unwrap_err(), "Token has no expiry");
+        let early = verify(serde_json::json!({ "sub": "alice", "exp": now() + 600, "nbf": now() + 300 }));
+        assert_eq!(early.unwrap_err(), "Token is not valid yet");
+        assert!(verify(serde_json::json!({ "sub": "alice", "exp": now() - 10 })).is_ok());
+    }
+
+    #[test]
+    fn jwt_dates_may_be_fractional_but_must_be_numbers() {
+        let header = serde_json::json!({ "alg": "HS256" });
+        let verify = |claims: Value| config().verify_jwt(&jwt(header.clone(), claims)).map_err(|e| e.to_string());
+
+        assert!(verify(serde_json::json!({ "sub": "alice", "exp": now() as f64 + 60.5, "nbf": 1.5 })).is_ok());
+        let early = verify(serde_json::json!({ "sub": "alice", "exp": now() + 600, "nbf": now() as f64 + 300.5 }));
+        assert_eq!(early.unwrap_err(), "Token is not valid yet");
+        let exp = verify(serde_json::json!({ "sub": "alice", "exp": "tomorrow" }));
+        assert_eq!(exp.unwrap_err(), "Token has an invalid `exp` claim");
+        let nbf = verify(serde_json::json!({ "sub": "alice", "exp": now() + 60, "nbf": "now" }));
+        assert_eq!(nbf.unwrap_err(), "Token has an invalid `nbf` claim");
+    }
+
+    #[test]
+    fn seen_signatures_are_forgotten_once_they_leave_the_window() {
+        let mut seen = SeenSignatures::default();
+        let key = ("ci".to_string(), b"signature".to_vec());
+        assert!(seen.first_use(key.clone(), 1_000, 1_000));
+        assert!(!seen.first_use(key.clone(), 1_000, 1_000 + MAX_SIGNATURE_AGE_SECS));
+        assert!(seen.first_use(("ci".to_string(), b"other".to_vec()), 1_100, 1_001 + MAX_SIGNATURE_AGE_SECS));
+        assert_eq!(seen.keys.len(), 1);
+        assert!(seen.first_use(key, 1_000, 1_001 + MAX_SIGNATURE_AGE_SECS));
+    }
+}
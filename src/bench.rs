@@ -0,0 +1,197 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use warp::http::{HeaderMap, Method};
+
+use crate::applet_store::AppletStore;
+use crate::cli::BenchArgs;
+use crate::commands::{applet_name, local_settings};
+use crate::record;
+use crate::runner::Runner;
+use crate::types::HttpRequest;
+
+/// What one worker measured
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    instantiate: Vec<Duration>,
+    errors: usize,
+    peak_memory: u64,
+}
+
+impl Samples {
+    fn merge(&mut self, other: Samples) {
+        self.latencies.extend(other.latencies);
+        self.instantiate.extend(other.instantiate);
+        self.errors += other.errors;
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+    }
+}
+
+/// `substrate bench`: drive each applet through the runner and report how it performed
+pub fn run(args: &BenchArgs) -> Result<()> {
+    if args.requests == 0 || args.concurrency == 0 {
+        return Err(anyhow!("--requests and --concurrency must be at least 1"));
+    }
+    let requests = Arc::new(requests(args)?);
+
+    for file in &args.files {
+        let path = Path::new(file);
+        let wasm_binary = std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+        // Each build gets its own store, so builds of the same applet (and
+        // with the same file name) can be compared
+        let runner = Arc::new(Runner::new(Arc::new(AppletStore::new()))?);
+
+        let compile_started = Instant::now();
+        let uuid = runner.install(wasm_binary, applet_name(path), local_settings())?;
+        let compile_time = compile_started.elapsed();
+
+        let next = Arc::new(AtomicUsize::new(0));
+        let started = Instant::now();
+        let workers: Vec<_> = (0..args.concurrency)
+            .map(|_| {
+                let (runner, requests, next) = (runner.clone(), requests.clone(), next.clone());
+                let total = args.requests;
+                std::thread::spawn(move || {
+                    let mut samples = Samples::default();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= total {
+                            return samples;
+                        }
+                        let request = requests[i % requests.len()].clone();
+                        let request_started = Instant::now();
+                        match runner.run_with_stats(uuid, request) {
+                            Ok((_, stats)) => {
+                                samples.latencies.push(request_started.elapsed());
+                                samples.instantiate.push(stats.instantiate_time);
+                                samples.peak_memory = samples.peak_memory.max(stats.memory_bytes);
+                            }
+                            Err(_) => samples.errors += 1,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut samples = Samples::default();
+        for worker in workers {
+            samples.merge(worker.join().map_err(|_| anyhow!("Benchmark worker panicked"))?);
+        }
+        let elapsed = started.elapsed();
+
+        report(path, args, compile_time, elapsed, &mut samples);
+    }
+
+    if let Some(peak) = process_peak_memory() {
+        println!("Process peak memory: {}", format_bytes(peak));
+    }
+    Ok(())
+}
+
+/// The request set: every request of the recording, or a synthetic `GET /`
+fn requests(args: &BenchArgs) -> Result<Vec<HttpRequest>> {
+    if let Some(path) = &args.recording {
+        let recordings = record::read(Path::new(path))?;
+        if recordings.is_empty() {
+            return Err(anyhow!("Recording '{}' holds no requests", path));
+        }
+        return recordings.iter().map(|recording| recording.request.to_request()).collect();
+    }
+
+    Ok(vec![HttpRequest {
+        method: Method::GET,
+        headers: HeaderMap::new(),
+        cookies: None,
+        path: "/".to_string(),
+        query: String::new(),
+        body: Bytes::from(args.data.clone().unwrap_or_default()),
+        body_stream: None,
+        remote_addr: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+        principal: None,
+        response_sink: None,
+    }])
+}
+
+fn report(path: &Path, args: &BenchArgs, compile_time: Duration, elapsed: Duration, samples: &mut Samples) {
+    samples.latencies.sort();
+    samples.instantiate.sort();
+    let completed = samples.latencies.len();
+
+    println!("{}", path.display());
+    println!("  compile       {}", format_duration(compile_time));
+    println!(
+        "  requests      {} ({} errors) at concurrency {}",
+        args.requests, samples.errors, args.concurrency
+    );
+    println!("  throughput    {:.1} req/s", completed as f64 / elapsed.as_secs_f64());
+    if completed > 0 {
+        println!(
+            "  latency       p50 {}  p90 {}  p99 {}  max {}",
+            format_duration(percentile(&samples.latencies, 50.0)),
+            format_duration(percentile(&samples.latencies, 90.0)),
+            format_duration(percentile(&samples.latencies, 99.0)),
+            format_duration(samples.latencies[completed - 1]),
+        );
+        let mean = samples.instantiate.iter().sum::<Duration>() / completed as u32;
+        println!(
+            "  instantiate   mean {}  p99 {}",
+            format_duration(mean),
+            format_duration(percentile(&samples.instantiate, 99.0)),
+        );
+        println!("  peak memory   {} (guest linear memory)", format_bytes(samples.peak_memory));
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Peak resident set size of this process, where the platform reports it
+fn process_peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|value| Duration::from_millis(*value)).collect()
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let samples = millis(&(1..=100).collect::<Vec<_>>());
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 90.0), Duration::from_millis(90));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&samples, 0.0), Duration::from_millis(1));
+
+        let samples = millis(&[10, 20, 30, 40]);
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(20));
+        assert_eq!(percentile(&samples, 51.0), Duration::from_millis(30));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(40));
+        assert_eq!(percentile(&millis(&[7]), 99.0), Duration::from_millis(7));
+    }
+}
@@ -1,39 +1,270 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
-/// Command-line arguments for the application
+/// Command-line arguments for the application. Flags override the
+/// configuration file and `SUBSTRATE_*` environment variables.
 #[derive(Parser, Debug, Clone)] // Added `Clone` here
 #[command(name = "WASM Server")]
 #[command(about = "A server for hosting and managing WASM applets", long_about = None)]
 pub struct CliArgs {
-    /// Hostname or IP address to bind the server
-    #[arg(long, default_value = "127.0.0.1")]
-    pub host: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
-    /// Port number for the server
-    #[arg(long, default_value = "3030")]
-    pub port: u16,
+    /// TOML configuration file
+    #[arg(long, global = true)]
+    pub config: Option<String>,
 
-    /// Time-to-live for applets in milliseconds
-    #[arg(long, default_value = "60000")]
-    pub ttl: u64,
+    /// Hostname or IP address to bind the server [default: 127.0.0.1]
+    #[arg(long, global = true)]
+    pub host: Option<String>,
 
-    /// WASM file to load and execute
-    #[arg(long)]
-    pub load: Option<String>,
+    /// Port number for the server [default: 3030]
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    /// Time-to-live for applets in milliseconds [default: 60000]
+    #[arg(long, global = true)]
+    pub ttl: Option<u64>,
+
+    /// Seconds to wait for in-flight requests on SIGTERM/SIGINT before interrupting guests [default: 30]
+    #[arg(long, global = true)]
+    pub shutdown_grace: Option<u64>,
+
+    /// WASM file to load, as `name=path` or just `path` (repeatable)
+    #[arg(long, global = true)]
+    pub load: Vec<String>,
+
+    /// Directory whose `.wasm` files are loaded and reloaded when they change
+    #[arg(long, global = true)]
+    pub watch: Option<String>,
+
+    /// Deployment manifest listing applets to load at startup
+    #[arg(long, global = true)]
+    pub manifest: Option<String>,
+
+    /// Enable the admin API (applet upload/delete, log tail, log topics)
+    #[arg(long, global = true)]
+    pub admin: bool,
+
+    /// Serve admin, metrics and log routes on their own listener (`host:port` or `unix:/path`)
+    #[arg(long, global = true)]
+    pub admin_listen: Option<String>,
+
+    /// Fuel available to each invocation (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub fuel: Option<u64>,
+
+    /// Largest linear memory an applet may grow to, in bytes (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub max_memory_bytes: Option<usize>,
+
+    /// Largest request body or WebSocket message an applet accepts, in bytes (0 = unlimited) [default: 10485760]
+    #[arg(long, global = true)]
+    pub max_body_bytes: Option<usize>,
+
+    /// Largest total size of request header names and values, in bytes (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub max_header_bytes: Option<usize>,
+
+    /// Seconds a guest waits on a slow client to send a streamed body or take a streamed response or WebSocket frames
+    /// (0 = unlimited) [default: 30]
+    #[arg(long, global = true)]
+    pub client_timeout: Option<u64>,
+
+    /// Requests per second each client may make to an applet (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub rate_limit: Option<u32>,
+
+    /// Requests a client may make in a burst (0 = same as --rate-limit) [default: 0]
+    #[arg(long, global = true)]
+    pub rate_burst: Option<u32>,
+
+    /// What identifies a client for rate limiting: ip, api_key, header:<name> (set by a trusted proxy) or applet [default: ip]
+    #[arg(long, global = true)]
+    pub rate_limit_key: Option<String>,
 
-    /// Logging topics (comma-separated list)
-    #[arg(long, value_delimiter = ',', use_value_delimiter = true)]
+    /// Invocations an applet may run at once (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub max_concurrency: Option<usize>,
+
+    /// Seconds a WebSocket may go without a message from the client (0 = unlimited) [default: 300]
+    #[arg(long, global = true)]
+    pub ws_idle_timeout: Option<u64>,
+
+    /// Seconds a WebSocket may stay open (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub ws_max_lifetime: Option<u64>,
+
+    /// Largest wasm binary accepted into the applet store, in bytes (0 = unlimited) [default: 0]
+    #[arg(long, global = true)]
+    pub max_applet_bytes: Option<usize>,
+
+    /// Events kept per channel for clients reconnecting with `Last-Event-ID` [default: 100]
+    #[arg(long, global = true)]
+    pub events_retain: Option<usize>,
+
+    /// Logging topics (comma-separated list, `prefix*` wildcards allowed)
+    #[arg(long, global = true, value_delimiter = ',', use_value_delimiter = true)]
     pub log: Vec<String>,
+
+    /// Minimum log levels (comma-separated `level` or `topic=level` entries)
+    #[arg(long, global = true, value_delimiter = ',', use_value_delimiter = true)]
+    pub log_level: Vec<String>,
+
+    /// File of extra logging topics (`topic` or `topic=level` per line), re-read on SIGHUP
+    #[arg(long, global = true)]
+    pub log_topics_file: Option<String>,
+
+    /// Log sinks (comma-separated: text, json, memory, file:<path>) [default: text]
+    #[arg(long, global = true, value_delimiter = ',', use_value_delimiter = true)]
+    pub log_sink: Vec<String>,
+
+    /// Rotate the log file once it reaches this many bytes (0 disables) [default: 10485760]
+    #[arg(long, global = true)]
+    pub log_file_max_bytes: Option<u64>,
+
+    /// Rotate the log file after this many seconds (0 disables) [default: 86400]
+    #[arg(long, global = true)]
+    pub log_file_max_age: Option<u64>,
+
+    /// Number of lines kept by the in-memory log sink [default: 1000]
+    #[arg(long, global = true)]
+    pub log_buffer_lines: Option<usize>,
+
+    /// Access log line format, logged under the `access` topic. Placeholders:
+    /// {remote} {method} {path} {applet} {status} {bytes} {duration_ms} {request_id}
+    #[arg(long, global = true)]
+    pub access_log_format: Option<String>,
+
+    /// OTLP/HTTP collector to export traces to (e.g. http://127.0.0.1:4318)
+    #[arg(long, global = true)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name reported with exported traces [default: substrate]
+    #[arg(long, global = true)]
+    pub otlp_service_name: Option<String>,
+
+    /// PEM certificate chain to serve HTTPS with (requires --tls-key)
+    #[arg(long, global = true)]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, global = true)]
+    pub tls_key: Option<String>,
+
+    /// PEM CA bundle; admin routes then require a client certificate signed by it
+    #[arg(long, global = true)]
+    pub tls_client_ca: Option<String>,
+
+    /// Record every request and its host call results to `<dir>/<applet>.jsonl`
+    #[arg(long, global = true)]
+    pub record: Option<String>,
+
+    /// Only record these applets (comma-separated names) [default: all]
+    #[arg(long, global = true, value_delimiter = ',', use_value_delimiter = true)]
+    pub record_applet: Vec<String>,
 }
 
-/// Parse and return the command-line arguments
-pub fn parse_args() -> CliArgs {
-    let mut args = CliArgs::parse();
+/// What to do; without a subcommand the server is started
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Serve applets over HTTP (the default)
+    Serve,
+    /// Invoke an applet once and print its response
+    Run(RunArgs),
+    /// Print a module's imports, exports and custom sections
+    Inspect {
+        /// WASM file to inspect
+        file: String,
+    },
+    /// Check that a module can be linked and run by this host
+    Validate {
+        /// WASM file to validate
+        file: String,
+    },
+    /// Measure the throughput and latency of one or more WASM files
+    Bench(BenchArgs),
+    /// Re-run recorded requests against a WASM file and diff the responses
+    Replay {
+        /// WASM file to replay against
+        file: String,
+        /// Recording written with `--record`
+        recording: String,
+    },
+    /// Inspect the effective configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+/// The applet and request for `substrate run`
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// WASM file to run
+    pub file: String,
+
+    /// JSON file describing the request (`method`, `path`, `query`,
+    /// `headers`, `body`); flags override its fields
+    #[arg(long)]
+    pub request: Option<String>,
+
+    /// Request method [default: GET]
+    #[arg(long, short = 'X')]
+    pub method: Option<String>,
+
+    /// Request path [default: /]
+    #[arg(long)]
+    pub path: Option<String>,
+
+    /// Raw query string, without the leading `?`
+    #[arg(long)]
+    pub query: Option<String>,
 
-    // Ensure "substrate" is always included in the logging topics
-    let mut topics: std::collections::HashSet<String> = args.log.into_iter().collect();
-    topics.insert("substrate".to_string());
+    /// Request header as `name: value` (repeatable)
+    #[arg(long, short = 'H')]
+    pub header: Vec<String>,
 
-    args.log = topics.into_iter().collect(); // Deduplicated and ensured "substrate"
-    args
+    /// Request body
+    #[arg(long, short = 'd', conflicts_with = "data_file")]
+    pub data: Option<String>,
+
+    /// File to read the request body from
+    #[arg(long)]
+    pub data_file: Option<String>,
+}
+
+/// The applets and workload for `substrate bench`
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// WASM files to benchmark, one after the other
+    #[arg(required = true)]
+    pub files: Vec<String>,
+
+    /// Number of requests sent to each applet
+    #[arg(long, short = 'n', default_value_t = 1000)]
+    pub requests: usize,
+
+    /// Number of requests in flight at once
+    #[arg(long, short = 'c', default_value_t = 1)]
+    pub concurrency: usize,
+
+    /// Recording (from `--record`) whose requests are sent in turn, instead
+    /// of a synthetic `GET /`
+    #[arg(long)]
+    pub recording: Option<String>,
+
+    /// Body of the synthetic request
+    #[arg(long, short = 'd')]
+    pub data: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigAction {
+    /// Validate the configuration and print the effective settings
+    Check,
+}
+
+/// Parse and return the command-line arguments
+pub fn parse_args() -> CliArgs {
+    CliArgs::parse()
 }
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use serde::Deserialize;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, Method, StatusCode};
+use wasmtime::{ExternType, Mutability};
+
+use crate::applet_store::{AppletSettings, AppletStore};
+use crate::cli::RunArgs;
+use crate::determinism;
+use crate::executor::Executor;
+use crate::record::{self, Outcome};
+use crate::runner::Runner;
+use crate::types::{HttpRequest, HttpResponse};
+
+/// A request read from the file given to `substrate run --request`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RequestFile {
+    method: Option<String>,
+    path: Option<String>,
+    query: Option<String>,
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+}
+
+/// `substrate run`: invoke the applet once and print its response
+pub fn run(args: &RunArgs) -> Result<()> {
+    let path = Path::new(&args.file);
+    let request = build_request(args)?;
+    let wasm_binary = std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    let store = Arc::new(AppletStore::new());
+    let runner = Runner::new(store)?;
+    let uuid = runner.install(wasm_binary, applet_name(path), local_settings())?;
+    let response = runner.run(uuid, request)?;
+    print_response(&response)
+}
+
+/// `substrate inspect`: print the module's imports, exports and custom sections
+pub fn inspect(path: &Path) -> Result<()> {
+    let wasm_binary = std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let executor = Executor::new(&wasm_binary, &AppletSettings::default())?;
+    let module = executor.module();
+
+    let mut out = String::new();
+    out.push_str("Imports:\n");
+    for import in module.imports() {
+        let _ = writeln!(out, "  {}.{}: {}", import.module(), import.name(), describe(&import.ty()));
+    }
+    out.push_str("Exports:\n");
+    for export in module.exports() {
+        let _ = writeln!(out, "  {}: {}", export.name(), describe(&export.ty()));
+    }
+    out.push_str("Custom sections:\n");
+    for (name, size) in custom_sections(&wasm_binary)? {
+        let _ = writeln!(out, "  {} ({} bytes)", name, size);
+    }
+    print!("{}", out);
+    Ok(())
+}
+
+/// `substrate validate`: check the module against the host linker
+pub fn validate(path: &Path) -> Result<()> {
+    let wasm_binary = std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    Executor::new(&wasm_binary, &AppletSettings::default())?.validate()?;
+    println!("{}: OK", path.display());
+    Ok(())
+}
+
+/// `substrate replay`: re-run every recorded request against the module,
+/// answering host calls from the recording, and report differences
+pub fn replay(path: &Path, recording_path: &Path) -> Result<()> {
+    let wasm_binary = std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let recordings = record::read(recording_path)?;
+
+    // Install under the recorded name so the same per-applet limits apply
+    let name = recordings.first().map(|recording| recording.applet.clone()).unwrap_or_else(|| applet_name(path));
+    let store = Arc::new(AppletStore::new());
+    let runner = Runner::new(store)?;
+    let uuid = runner.install(wasm_binary, name, local_settings())?;
+
+    let mut mismatches = 0;
+    for (i, recording) in recordings.iter().enumerate() {
+        let mut request = recording.request.to_request()?;
+        // Re-use the seed of a deterministic run the applet picked itself
+        if let Outcome::Response(response) = &recording.outcome {
+            if let Some((_, seed)) = response.headers.iter().find(|(name, _)| name == determinism::SEED_HEADER) {
+                if !request.headers.contains_key(determinism::SEED_HEADER) {
+                    request.headers.insert(determinism::SEED_HEADER, HeaderValue::from_str(seed)?);
+                }
+            }
+        }
+        let result = {
+            let _tape = record::start_replay(recording.host_calls.clone());
+            runner.run(uuid, request)
+        };
+        // Credentials were redacted when recording, so they are not compared
+        let outcome = Outcome::new(&result).without_redacted();
+        let recorded = recording.outcome.without_redacted();
+
+        let label = format!(
+            "#{} {} {} (request {})",
+            i + 1,
+            recording.request.method,
+            recording.request.path,
+            recording.request.request_id
+        );
+        if outcome == recorded {
+            println!("{}: OK", label);
+        } else {
+            mismatches += 1;
+            println!("{}: MISMATCH", label);
+            print!("{}", diff(&recorded, &outcome));
+        }
+    }
+
+    println!("{} replayed, {} mismatched", recordings.len(), mismatches);
+    if mismatches > 0 {
+        return Err(anyhow!("{} of {} responses differ", mismatches, recordings.len()));
+    }
+    Ok(())
+}
+
+/// Describe how a replayed outcome differs from the recorded one
+fn diff(recorded: &Outcome, replayed: &Outcome) -> String {
+    let mut out = String::new();
+    match (recorded, replayed) {
+        (Outcome::Response(recorded), Outcome::Response(replayed)) => {
+            if recorded.status != replayed.status {
+                let _ = writeln!(out, "  status: {} -> {}", recorded.status, replayed.status);
+            }
+            if recorded.headers != replayed.headers {
+                for header in &recorded.headers {
+                    if !replayed.headers.contains(header) {
+                        let _ = writeln!(out, "  - header {}: {}", header.0, header.1);
+                    }
+                }
+                for header in &replayed.headers {
+                    if !recorded.headers.contains(header) {
+                        let _ = writeln!(out, "  + header {}: {}", header.0, header.1);
+                    }
+                }
+            }
+            if recorded.body != replayed.body {
+                out.push_str("  body:\n");
+                for line in String::from_utf8_lossy(&record::decode_body(&recorded.body)).lines() {
+                    let _ = writeln!(out, "  - {}", line);
+                }
+                for line in String::from_utf8_lossy(&record::decode_body(&replayed.body)).lines() {
+                    let _ = writeln!(out, "  + {}", line);
+                }
+            }
+        }
+        (recorded, replayed) => {
+            let _ = writeln!(out, "  - {}", describe_outcome(recorded));
+            let _ = writeln!(out, "  + {}", describe_outcome(replayed));
+        }
+    }
+    out
+}
+
+fn describe_outcome(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Response(response) => format!("response {}", response.status),
+        Outcome::Error(message) => format!("error: {}", message),
+    }
+}
+
+/// Applet name for a file given on the command line: its file stem
+pub fn applet_name(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Settings for an applet run from the command line, where whoever runs it
+/// may pick the seed of a deterministic run
+pub fn local_settings() -> AppletSettings {
+    AppletSettings { allow_seed_header: true, ..AppletSettings::default() }
+}
+
+/// Build the request from the request file, overridden by flags
+fn build_request(args: &RunArgs) -> Result<HttpRequest> {
+    let file = match &args.request {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path))?;
+            serde_json::from_str::<RequestFile>(&contents).with_context(|| format!("Invalid request file '{}'", path))?
+        }
+        None => RequestFile::default(),
+    };
+
+    let method = args.method.clone().or(file.method).unwrap_or_else(|| "GET".to_string());
+    let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| anyhow!("Invalid method '{}'", method))?;
+
+    let mut headers = HeaderMap::new();
+    let flag_headers = args.header.iter().map(|header| {
+        header
+            .split_once(':')
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .ok_or_else(|| anyhow!("Invalid header '{}': expected `name: value`", header))
+    });
+    for header in file.headers.into_iter().map(Ok).chain(flag_headers) {
+        let (name, value) = header?;
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow!("Invalid header name '{}'", name))?,
+            HeaderValue::from_str(&value).map_err(|_| anyhow!("Invalid value for header '{}'", name))?,
+        );
+    }
+
+    let body = match (&args.data, &args.data_file) {
+        (Some(data), _) => Bytes::from(data.clone()),
+        (None, Some(path)) => Bytes::from(std::fs::read(path).with_context(|| format!("Failed to read '{}'", path))?),
+        (None, None) => Bytes::from(file.body.unwrap_or_default()),
+    };
+
+    Ok(HttpRequest {
+        method,
+        cookies: headers.get("cookie").and_then(|value| value.to_str().ok()).map(String::from),
+        headers,
+        path: args.path.clone().or(file.path).unwrap_or_else(|| "/".to_string()),
+        query: args.query.clone().or(file.query).unwrap_or_default(),
+        body,
+        body_stream: None,
+        remote_addr: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+        principal: None,
+        response_sink: None,
+    })
+}
+
+/// Print the response as a status line, headers, a blank line and the body
+fn print_response(response: &HttpResponse) -> Result<()> {
+    let reason = StatusCode::from_u16(response.status_code)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "{} {}", response.status_code, reason)?;
+    for (name, value) in &response.headers {
+        writeln!(out, "{}: {}", name, String::from_utf8_lossy(value.as_bytes()))?;
+    }
+    writeln!(out)?;
+    out.write_all(&response.body)?;
+    if !response.body.ends_with(b"\n") {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// One-line description of an import or export type
+fn describe(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Func(func) => format!(
+            "func ({}) -> ({})",
+            func.params().map(|ty| ty.to_string()).collect::<Vec<_>>().join(", "),
+            func.results().map(|ty| ty.to_string()).collect::<Vec<_>>().join(", ")
+        ),
+        ExternType::Global(global) => format!(
+            "global {}{}",
+            if global.mutability() == Mutability::Var { "mut " } else { "" },
+            global.content()
+        ),
+        ExternType::Table(table) => format!(
+            "table {} {}",
+            table.element(),
+            limits(table.minimum().into(), table.maximum().map(u64::from))
+        ),
+        ExternType::Memory(memory) => format!("memory {} pages", limits(memory.minimum(), memory.maximum())),
+    }
+}
+
+/// Render size limits as `min..max`, or `min+` without a maximum
+fn limits(minimum: u64, maximum: Option<u64>) -> String {
+    match maximum {
+        Some(maximum) => format!("{}..{}", minimum, maximum),
+        None => format!("{}+", minimum),
+    }
+}
+
+/// Name and size of every custom section; text modules have none
+fn custom_sections(wasm_binary: &[u8]) -> Result<Vec<(String, usize)>> {
+    if !wasm_binary.starts_with(b"\0asm") {
+        return Ok(Vec::new());
+    }
+    let mut sections = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm_binary) {
+        if let wasmparser::Payload::CustomSection(reader) = payload? {
+            sections.push((reader.name().to_string(), reader.data().len()));
+        }
+    }
+    Ok(sections)
+}
@@ -1,26 +1,400 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use crate::auth::AuthConfig;
 use crate::cli::CliArgs; // Import the CliArgs structure
+use crate::cors::CorsPolicy;
+use crate::config_file::{
+    AdminSection, EventsSection, FileConfig, LimitsSection, LoggingSection, RecordingSection, ServerSection, StorageSection,
+    TlsSection, TracingSection,
+};
+use crate::log::Level;
+use crate::log_sink::SinkKind;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 3030;
+const DEFAULT_TTL: u64 = 60000;
+const DEFAULT_SHUTDOWN_GRACE: u64 = 30;
+const DEFAULT_WS_IDLE_TIMEOUT: u64 = 300;
+const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_CLIENT_TIMEOUT: u64 = 30;
+const DEFAULT_EVENTS_RETAIN: usize = 100;
+const DEFAULT_LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_FILE_MAX_AGE: u64 = 24 * 60 * 60;
+const DEFAULT_LOG_BUFFER_LINES: usize = 1000;
+const DEFAULT_ACCESS_LOG_FORMAT: &str =
+    "{remote} \"{method} {path}\" {status} {bytes} {duration_ms}ms applet={applet} request={request_id}";
+const DEFAULT_SERVICE_NAME: &str = "substrate";
+
+/// Resource limits applied to each invocation; 0 means unlimited
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub fuel: u64,               // Fuel available to one invocation
+    pub max_memory_bytes: usize, // Largest linear memory a guest may grow to
+    pub max_body_bytes: usize,   // Largest request body or WebSocket message accepted
+    pub max_header_bytes: usize, // Largest total size of request headers
+    pub client_timeout: u64,     // Seconds a guest waits on a slow client to send or take data
+    pub rate_limit: u32,         // Requests per second per client
+    pub rate_burst: u32,         // Token bucket size (0 = same as `rate_limit`)
+    pub rate_limit_key: RateLimitKey, // What identifies a client
+    pub max_concurrency: usize,  // Invocations of the applet running at once
+    pub ws_idle_timeout: u64,    // Seconds a WebSocket may go without a message from the client
+    pub ws_max_lifetime: u64,    // Seconds a WebSocket may stay open
+}
+
+impl Limits {
+    /// How long a guest waits on a slow client, if there is a limit
+    pub fn client_wait(&self) -> Option<Duration> {
+        (self.client_timeout > 0).then(|| Duration::from_secs(self.client_timeout))
+    }
+
+    /// These limits with the ones set in `overrides` replaced; rate limit
+    /// keys are validated when the configuration is loaded and when an
+    /// applet is stored
+    pub fn with(&self, overrides: &LimitsSection) -> Limits {
+        Limits {
+            fuel: overrides.fuel.unwrap_or(self.fuel),
+            max_memory_bytes: overrides.max_memory_bytes.unwrap_or(self.max_memory_bytes),
+            max_body_bytes: overrides.max_body_bytes.unwrap_or(self.max_body_bytes),
+            max_header_bytes: overrides.max_header_bytes.unwrap_or(self.max_header_bytes),
+            client_timeout: overrides.client_timeout.unwrap_or(self.client_timeout),
+            rate_limit: overrides.rate_limit.unwrap_or(self.rate_limit),
+            rate_burst: overrides.rate_burst.unwrap_or(self.rate_burst),
+            rate_limit_key: overrides
+                .rate_limit_key
+                .as_deref()
+                .and_then(|key| RateLimitKey::parse(key).ok())
+                .unwrap_or_else(|| self.rate_limit_key.clone()),
+            max_concurrency: overrides.max_concurrency.unwrap_or(self.max_concurrency),
+            ws_idle_timeout: overrides.ws_idle_timeout.unwrap_or(self.ws_idle_timeout),
+            ws_max_lifetime: overrides.ws_max_lifetime.unwrap_or(self.ws_max_lifetime),
+        }
+    }
+}
+
+/// What a client is identified by for rate limiting
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    #[default]
+    Ip,             // Remote IP address
+    ApiKey,         // The configured API key in `x-api-key`, else the IP
+    Header(String), // A request header, lowercased; one a trusted proxy sets
+    Applet,         // One bucket shared by every client
+}
+
+impl RateLimitKey {
+    /// Parse `ip`, `api_key`, `header:<name>` or `applet`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "ip" => Ok(RateLimitKey::Ip),
+            "api_key" => Ok(RateLimitKey::ApiKey),
+            "applet" => Ok(RateLimitKey::Applet),
+            _ => match value.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => Ok(RateLimitKey::Header(name.to_ascii_lowercase())),
+                _ => Err(anyhow!(
+                    "Invalid rate limit key '{}': expected ip, api_key, header:<name> or applet",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitKey::Ip => write!(f, "ip"),
+            RateLimitKey::ApiKey => write!(f, "api_key"),
+            RateLimitKey::Header(name) => write!(f, "header:{}", name),
+            RateLimitKey::Applet => write!(f, "applet"),
+        }
+    }
+}
+
+/// Certificate files for serving HTTPS
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,              // PEM certificate chain
+    pub key: PathBuf,               // PEM private key
+    pub client_ca: Option<PathBuf>, // CA for admin client certificates (mTLS)
+}
+
+/// Where the admin listener accepts connections
+#[derive(Clone, Debug)]
+pub enum AdminListen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl AdminListen {
+    /// Parse `host:port` or `unix:/path/to/socket`
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            return Ok(AdminListen::Unix(PathBuf::from(path)));
+        }
+        value
+            .parse()
+            .map(AdminListen::Tcp)
+            .map_err(|_| anyhow!("Invalid admin listen address '{}': expected host:port or unix:/path", value))
+    }
+}
+
+impl fmt::Display for AdminListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminListen::Tcp(addr) => write!(f, "{}", addr),
+            AdminListen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
 
 #[derive(Debug)] // Automatically implements Debug for Config
 pub struct Config {
+    pub config_file: Option<PathBuf>, // File the settings were read from
     pub host: String,         // Hostname or IP
     pub port: u16,            // Port number
+    #[allow(dead_code)]
     pub ttl: u64,             // Time-to-live in milliseconds
+    pub tls: Option<TlsConfig>, // Serve HTTPS instead of HTTP
+    pub shutdown_grace: Duration, // How long shutdown waits for in-flight requests
+    pub manifest: Option<PathBuf>, // Deployment manifest loaded at startup
+    pub watch_dir: Option<PathBuf>, // Directory of applets reloaded on change
+    pub admin_enabled: bool,  // Whether the admin API is served
+    pub admin_listen: Option<AdminListen>, // Separate listener for admin, metrics and log routes
+    pub auth: AuthConfig,     // Admin tokens, applet auth policy and credentials
+    pub cors: CorsPolicy,     // Cross-origin requests browsers may make to applets
+    pub limits: Limits,       // Default limits for every applet
+    pub applet_limits: BTreeMap<String, LimitsSection>, // Per-applet overrides by name
+    pub max_applet_bytes: usize, // Largest wasm binary accepted into the store (0 = unlimited)
+    pub events_retain: usize, // Events kept per channel for clients that reconnect
     pub log_topics: HashSet<String>, // Logging topics
+    pub log_level: Level,     // Minimum level for topics without an override
+    pub log_levels: HashMap<String, Level>, // Per-topic minimum levels
+    pub log_topics_file: Option<PathBuf>, // Extra topics, re-read on SIGHUP
+    pub log_sinks: Vec<SinkKind>, // Where log lines are written
+    pub log_file_max_bytes: u64, // Rotate the log file past this size (0 = never)
+    pub log_file_max_age: u64, // Rotate the log file after this many seconds (0 = never)
+    pub log_buffer_lines: usize, // Lines kept by the in-memory sink
+    pub access_log_format: String, // Template for `access` topic lines
+    pub otlp_endpoint: Option<String>, // OTLP/HTTP collector for traces
+    pub otlp_service_name: String, // Service name reported with traces
+    pub record_dir: Option<PathBuf>, // Where request recordings are written
+    pub record_applets: Vec<String>, // Applets to record (empty = all)
+}
+
+impl Config {
+    /// Build the effective configuration: built-in defaults, overridden by
+    /// the configuration file, then `SUBSTRATE_*` variables, then CLI flags
+    pub fn resolve(args: CliArgs) -> Result<Self> {
+        let config_file = args.config.map(PathBuf::from);
+        let file = FileConfig::load(config_file.as_deref())?;
+
+        let host = args.host.or(file.server.host).unwrap_or_else(|| DEFAULT_HOST.to_string());
+        host.parse::<IpAddr>()
+            .map_err(|_| anyhow!("Invalid host '{}': expected an IP address", host))?;
+
+        let tls = match (args.tls_cert.or(file.tls.cert), args.tls_key.or(file.tls.key)) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: args.tls_client_ca.or(file.tls.client_ca).map(PathBuf::from),
+            }),
+            (None, None) => None,
+            _ => return Err(anyhow!("TLS needs both a certificate and a key")),
+        };
+
+        let log_topics = non_empty(args.log).or(file.logging.topics).unwrap_or_default();
+        let log_levels = non_empty(args.log_level).or(file.logging.levels).unwrap_or_default();
+        let (log_level, log_levels) = parse_log_levels(&log_levels)?;
+
+        let log_sinks = non_empty(args.log_sink)
+            .or(file.logging.sinks)
+            .unwrap_or_else(|| vec!["text".to_string()])
+            .iter()
+            .map(|sink| SinkKind::parse(sink).ok_or_else(|| anyhow!("Invalid log sink '{}'", sink)))
+            .collect::<Result<Vec<_>>>()?;
+
+        for (name, overrides) in &file.applets {
+            if let Some(key) = &overrides.rate_limit_key {
+                RateLimitKey::parse(key).map_err(|e| anyhow!("Applet '{}': {}", name, e))?;
+            }
+        }
+
+        Ok(Config {
+            config_file,
+            host,
+            port: args.port.or(file.server.port).unwrap_or(DEFAULT_PORT),
+            ttl: args.ttl.or(file.server.ttl).unwrap_or(DEFAULT_TTL),
+            tls,
+            shutdown_grace: Duration::from_secs(
+                args.shutdown_grace.or(file.server.shutdown_grace).unwrap_or(DEFAULT_SHUTDOWN_GRACE),
+            ),
+            manifest: args.manifest.or(file.server.manifest).map(PathBuf::from),
+            watch_dir: args.watch.or(file.server.watch).map(PathBuf::from),
+            admin_enabled: args.admin || file.admin.enabled.unwrap_or(false),
+            admin_listen: args
+                .admin_listen
+                .or(file.admin.listen)
+                .map(|value| AdminListen::parse(&value))
+                .transpose()?,
+            auth: AuthConfig::resolve(file.auth)?,
+            cors: CorsPolicy::resolve(&file.cors)?,
+            limits: Limits {
+                fuel: args.fuel.or(file.limits.fuel).unwrap_or(0),
+                max_memory_bytes: args.max_memory_bytes.or(file.limits.max_memory_bytes).unwrap_or(0),
+                max_body_bytes: args.max_body_bytes.or(file.limits.max_body_bytes).unwrap_or(DEFAULT_MAX_BODY_BYTES),
+                max_header_bytes: args.max_header_bytes.or(file.limits.max_header_bytes).unwrap_or(0),
+                client_timeout: args
+                    .client_timeout
+                    .or(file.limits.client_timeout)
+                    .unwrap_or(DEFAULT_CLIENT_TIMEOUT),
+                rate_limit: args.rate_limit.or(file.limits.rate_limit).unwrap_or(0),
+                rate_burst: args.rate_burst.or(file.limits.rate_burst).unwrap_or(0),
+                rate_limit_key: args
+                    .rate_limit_key
+                    .or(file.limits.rate_limit_key)
+                    .map(|key| RateLimitKey::parse(&key))
+                    .transpose()?
+                    .unwrap_or_default(),
+                max_concurrency: args.max_concurrency.or(file.limits.max_concurrency).unwrap_or(0),
+                ws_idle_timeout: args
+                    .ws_idle_timeout
+                    .or(file.limits.ws_idle_timeout)
+                    .unwrap_or(DEFAULT_WS_IDLE_TIMEOUT),
+                ws_max_lifetime: args.ws_max_lifetime.or(file.limits.ws_max_lifetime).unwrap_or(0),
+            },
+            applet_limits: file.applets,
+            max_applet_bytes: args.max_applet_bytes.or(file.storage.max_applet_bytes).unwrap_or(0),
+            events_retain: args.events_retain.or(file.events.retain).unwrap_or(DEFAULT_EVENTS_RETAIN),
+            // "substrate" is always logged
+            log_topics: log_topics.into_iter().chain(["substrate".to_string()]).collect(),
+            log_level,
+            log_levels,
+            log_topics_file: args.log_topics_file.or(file.logging.topics_file).map(PathBuf::from),
+            log_sinks,
+            log_file_max_bytes: args
+                .log_file_max_bytes
+                .or(file.logging.file_max_bytes)
+                .unwrap_or(DEFAULT_LOG_FILE_MAX_BYTES),
+            log_file_max_age: args
+                .log_file_max_age
+                .or(file.logging.file_max_age)
+                .unwrap_or(DEFAULT_LOG_FILE_MAX_AGE),
+            log_buffer_lines: args
+                .log_buffer_lines
+                .or(file.logging.buffer_lines)
+                .unwrap_or(DEFAULT_LOG_BUFFER_LINES),
+            access_log_format: args
+                .access_log_format
+                .or(file.logging.access_log_format)
+                .unwrap_or_else(|| DEFAULT_ACCESS_LOG_FORMAT.to_string()),
+            otlp_endpoint: args.otlp_endpoint.or(file.tracing.otlp_endpoint),
+            otlp_service_name: args
+                .otlp_service_name
+                .or(file.tracing.service_name)
+                .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string()),
+            record_dir: args.record.or(file.recording.dir).map(PathBuf::from),
+            record_applets: non_empty(args.record_applet).or(file.recording.applets).unwrap_or_default(),
+        })
+    }
+
+    /// Limits for the applet called `name`, with its overrides applied
+    pub fn limits_for(&self, name: &str) -> Limits {
+        match self.applet_limits.get(name) {
+            Some(overrides) => self.limits.with(overrides),
+            None => self.limits.clone(),
+        }
+    }
+
+    /// The effective configuration in configuration file form
+    pub fn to_file_config(&self) -> FileConfig {
+        let mut topics: Vec<String> = self.log_topics.iter().cloned().collect();
+        topics.sort();
+        let mut levels = vec![self.log_level.as_str().to_ascii_lowercase()];
+        let mut overrides: Vec<String> = self
+            .log_levels
+            .iter()
+            .map(|(topic, level)| format!("{}={}", topic, level.as_str().to_ascii_lowercase()))
+            .collect();
+        overrides.sort();
+        levels.extend(overrides);
+
+        FileConfig {
+            server: ServerSection {
+                host: Some(self.host.clone()),
+                port: Some(self.port),
+                ttl: Some(self.ttl),
+                manifest: self.manifest.as_ref().map(|p| p.display().to_string()),
+                shutdown_grace: Some(self.shutdown_grace.as_secs()),
+                watch: self.watch_dir.as_ref().map(|p| p.display().to_string()),
+            },
+            tls: TlsSection {
+                cert: self.tls.as_ref().map(|tls| tls.cert.display().to_string()),
+                key: self.tls.as_ref().map(|tls| tls.key.display().to_string()),
+                client_ca: self
+                    .tls
+                    .as_ref()
+                    .and_then(|tls| tls.client_ca.as_ref())
+                    .map(|p| p.display().to_string()),
+            },
+            admin: AdminSection {
+                enabled: Some(self.admin_enabled),
+                listen: self.admin_listen.as_ref().map(|listen| listen.to_string()),
+            },
+            auth: self.auth.to_section(),
+            cors: self.cors.to_section(),
+            limits: LimitsSection {
+                fuel: Some(self.limits.fuel),
+                max_memory_bytes: Some(self.limits.max_memory_bytes),
+                max_body_bytes: Some(self.limits.max_body_bytes),
+                max_header_bytes: Some(self.limits.max_header_bytes),
+                client_timeout: Some(self.limits.client_timeout),
+                rate_limit: Some(self.limits.rate_limit),
+                rate_burst: Some(self.limits.rate_burst),
+                rate_limit_key: Some(self.limits.rate_limit_key.to_string()),
+                max_concurrency: Some(self.limits.max_concurrency),
+                ws_idle_timeout: Some(self.limits.ws_idle_timeout),
+                ws_max_lifetime: Some(self.limits.ws_max_lifetime),
+            },
+            logging: LoggingSection {
+                topics: Some(topics),
+                levels: Some(levels),
+                topics_file: self.log_topics_file.as_ref().map(|p| p.display().to_string()),
+                sinks: Some(self.log_sinks.iter().map(SinkKind::to_string).collect()),
+                file_max_bytes: Some(self.log_file_max_bytes),
+                file_max_age: Some(self.log_file_max_age),
+                buffer_lines: Some(self.log_buffer_lines),
+                access_log_format: Some(self.access_log_format.clone()),
+            },
+            tracing: TracingSection {
+                otlp_endpoint: self.otlp_endpoint.clone(),
+                service_name: Some(self.otlp_service_name.clone()),
+            },
+            recording: RecordingSection {
+                dir: self.record_dir.as_ref().map(|p| p.display().to_string()),
+                applets: Some(self.record_applets.clone()),
+            },
+            storage: StorageSection {
+                max_applet_bytes: Some(self.max_applet_bytes),
+            },
+            events: EventsSection {
+                retain: Some(self.events_retain),
+            },
+            applets: self.applet_limits.clone(),
+        }
+    }
 }
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
 /// Initialize the global configuration
-pub fn init_config(args: CliArgs) {
+pub fn init_config(config: Config) {
     CONFIG
-        .set(Config {
-            host: args.host,
-            port: args.port,
-            ttl: args.ttl,
-            log_topics: args.log.into_iter().collect(), // Convert Vec<String> to HashSet<String>
-        })
+        .set(config)
         .expect("Config has already been initialized!");
 }
 
@@ -28,3 +402,95 @@ pub fn init_config(args: CliArgs) {
 pub fn global_config() -> &'static Config {
     CONFIG.get().expect("Config has not been initialized!")
 }
+
+/// Validate the configuration and print the effective settings as TOML;
+/// used by `substrate config check`
+pub fn check(args: CliArgs) -> Result<String> {
+    let config = Config::resolve(args)?;
+    if let Some(path) = &config.log_topics_file {
+        check_readable(path)?;
+    }
+    if let Some(path) = &config.manifest {
+        crate::manifest::load(path)?;
+    }
+    if let Some(tls) = &config.tls {
+        crate::tls::load(tls)?;
+    }
+    if let Some(path) = &config.watch_dir {
+        if !path.is_dir() {
+            return Err(anyhow!("Watch directory '{}' is not a directory", path.display()));
+        }
+    }
+    Ok(toml::to_string_pretty(&config.to_file_config())?)
+}
+
+fn check_readable(path: &Path) -> Result<()> {
+    std::fs::metadata(path)
+        .map(|_| ())
+        .map_err(|e| anyhow!("Cannot read '{}': {}", path.display(), e))
+}
+
+/// Treat an empty list of CLI values as "not given"
+fn non_empty(values: Vec<String>) -> Option<Vec<String>> {
+    (!values.is_empty()).then_some(values)
+}
+
+/// Split log level entries into a default level and per-topic overrides.
+/// Entries are either `level` or `topic=level`.
+fn parse_log_levels(entries: &[String]) -> Result<(Level, HashMap<String, Level>)> {
+    let mut default = Level::Info;
+    let mut levels = HashMap::new();
+
+    for entry in entries {
+        let invalid = || anyhow!("Invalid log level '{}'", entry);
+        match entry.split_once('=') {
+            Some((topic, level)) => {
+                levels.insert(topic.to_string(), Level::parse(level).ok_or_else(invalid)?);
+            }
+            None => default = Level::parse(entry).ok_or_else(invalid)?,
+        }
+    }
+
+    Ok((default, levels))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn admin_listen_addresses() {
+        assert!(matches!(AdminListen::parse("127.0.0.1:9090").unwrap(), AdminListen::Tcp(addr) if addr.port() == 9090));
+        let unix = AdminListen::parse("unix:/run/substrate.sock").unwrap();
+        assert!(matches!(&unix, AdminListen::Unix(path) if path == Path::new("/run/substrate.sock")));
+        assert_eq!(unix.to_string(), "unix:/run/substrate.sock");
+        assert!(AdminListen::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn check_output_round_trips() {
+        let output = check(CliArgs::parse_from(["substrate"])).unwrap();
+
+        let path = std::env::temp_dir().join(format!("substrate-check-{}.toml", std::process::id()));
+        std::fs::write(&path, &output).unwrap();
+        let reread = check(CliArgs::parse_from(["substrate", "--config", path.to_str().unwrap()]));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reread.unwrap(), output);
+    }
+
+    #[test]
+    fn check_prints_effective_settings_with_secrets_redacted() {
+        let path = std::env::temp_dir().join(format!("substrate-check-secrets-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\nport = 9000\n\n[auth.api_keys]\nci = \"hunter2\"\n").unwrap();
+        let output = check(CliArgs::parse_from(["substrate", "--config", path.to_str().unwrap()]));
+        std::fs::remove_file(&path).unwrap();
+
+        let output: FileConfig = toml::from_str(&output.unwrap()).unwrap();
+        assert_eq!(output.server.port, Some(9000));
+        assert_eq!(output.server.host.as_deref(), Some(DEFAULT_HOST));
+        assert_eq!(output.auth.api_keys.unwrap()["ci"], "<redacted>");
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of environment variables that override the configuration file
+const ENV_PREFIX: &str = "SUBSTRATE_";
+
+/// Sections that can be overridden from the environment
+const ENV_SECTIONS: [&str; 11] =
+    ["server", "tls", "admin", "auth", "cors", "limits", "logging", "tracing", "recording", "storage", "events"];
+
+/// Keys holding lists, where a single environment value means a one-item list
+const ENV_LIST_KEYS: [&str; 7] = ["topics", "levels", "sinks", "applets", "origins", "methods", "headers"];
+
+/// Contents of a `substrate.toml` file. Every setting is optional; anything
+/// left out falls back to the built-in default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub server: ServerSection,
+    pub tls: TlsSection,
+    pub admin: AdminSection,
+    pub auth: AuthSection,
+    pub cors: CorsSection,
+    pub limits: LimitsSection,
+    pub logging: LoggingSection,
+    pub tracing: TracingSection,
+    pub recording: RecordingSection,
+    pub storage: StorageSection,
+    pub events: EventsSection,
+    pub applets: BTreeMap<String, LimitsSection>, // Per-applet overrides, keyed by applet name
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub ttl: Option<u64>,
+    pub manifest: Option<String>,
+    pub watch: Option<String>,
+    pub shutdown_grace: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    pub enabled: Option<bool>,
+    pub listen: Option<String>,
+}
+
+/// Secrets are plain values here; `config check` prints them redacted
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub applet_policy: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub api_keys: Option<BTreeMap<String, String>>,
+    pub hmac_keys: Option<BTreeMap<String, String>>,
+    pub admin_tokens: Option<Vec<AdminTokenSection>>,
+    pub jwt_keys: Option<Vec<JwtKeySection>>,
+}
+
+/// One `[[auth.admin_tokens]]` entry
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminTokenSection {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// One `[[auth.jwt_keys]]` entry
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtKeySection {
+    pub kid: Option<String>,
+    pub algorithm: String,
+    pub secret: Option<String>,
+    pub public_key_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSection {
+    pub origins: Option<Vec<String>>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub credentials: Option<bool>,
+    pub max_age: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub fuel: Option<u64>,
+    pub max_memory_bytes: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub client_timeout: Option<u64>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+    pub rate_limit_key: Option<String>,
+    pub max_concurrency: Option<usize>,
+    pub ws_idle_timeout: Option<u64>,
+    pub ws_max_lifetime: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub topics: Option<Vec<String>>,
+    pub levels: Option<Vec<String>>,
+    pub topics_file: Option<String>,
+    pub sinks: Option<Vec<String>>,
+    pub file_max_bytes: Option<u64>,
+    pub file_max_age: Option<u64>,
+    pub buffer_lines: Option<usize>,
+    pub access_log_format: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSection {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingSection {
+    pub dir: Option<String>,
+    pub applets: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    pub max_applet_bytes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsSection {
+    pub retain: Option<usize>,
+}
+
+impl FileConfig {
+    /// Read the configuration file, if any, and apply `SUBSTRATE_*`
+    /// environment overrides on top of it
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+                contents
+                    .parse::<toml::Value>()
+                    .with_context(|| format!("Failed to parse config file '{}'", path.display()))?
+            }
+            None => toml::Value::Table(Default::default()),
+        };
+
+        apply_env(&mut table, std::env::vars())?;
+
+        table.try_into().map_err(|e| anyhow!("Invalid configuration: {}", e))
+    }
+}
+
+/// Overlay `SUBSTRATE_<SECTION>_<KEY>=value` variables onto the parsed file,
+/// e.g. `SUBSTRATE_SERVER_PORT=8080` or `SUBSTRATE_LOGGING_TOPICS=substrate,access`
+fn apply_env(table: &mut toml::Value, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+    for (name, value) in vars {
+        let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let rest = rest.to_ascii_lowercase();
+        let Some((section, key)) = rest.split_once('_') else {
+            continue;
+        };
+        if !ENV_SECTIONS.contains(&section) {
+            continue;
+        }
+
+        let root = table.as_table_mut().ok_or_else(|| anyhow!("Configuration must be a table"))?;
+        let section_table = root
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Configuration section '{}' must be a table", section))?;
+        section_table.insert(key.to_string(), env_value(&value, ENV_LIST_KEYS.contains(&key)));
+    }
+    Ok(())
+}
+
+/// Interpret an environment value as TOML when it parses as one (numbers,
+/// booleans, arrays), otherwise as a plain string. List keys also accept a
+/// comma-separated string.
+fn env_value(value: &str, list: bool) -> toml::Value {
+    if let Ok(toml::Value::Table(mut parsed)) = format!("value = {}", value).parse::<toml::Value>() {
+        if let Some(parsed) = parsed.remove("value") {
+            if parsed.is_array() || !list {
+                return parsed;
+            }
+        }
+    }
+    if list {
+        return toml::Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect(),
+        );
+    }
+    toml::Value::String(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(file: &str, vars: &[(&str, &str)]) -> Result<FileConfig> {
+        let mut table = file.parse::<toml::Value>()?;
+        apply_env(&mut table, vars.iter().map(|(name, value)| (name.to_string(), value.to_string())))?;
+        Ok(table.try_into()?)
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let config = overlay(
+            "[server]\nport = 3000\nhost = \"0.0.0.0\"\n",
+            &[("SUBSTRATE_SERVER_PORT", "8080"), ("SUBSTRATE_LIMITS_MAX_BODY_BYTES", "1024")],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, Some(8080));
+        assert_eq!(config.server.host.as_deref(), Some("0.0.0.0"));
+        assert_eq!(config.limits.max_body_bytes, Some(1024));
+    }
+
+    #[test]
+    fn env_values_fall_back_to_strings_and_lists() {
+        let config = overlay(
+            "",
+            &[
+                ("SUBSTRATE_SERVER_HOST", "127.0.0.1"),
+                ("SUBSTRATE_LOGGING_TOPICS", "substrate, access"),
+                ("SUBSTRATE_LOGGING_SINKS", "json"),
+                ("SUBSTRATE_CORS_ORIGINS", "[\"https://a.example\"]"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.host.as_deref(), Some("127.0.0.1"));
+        assert_eq!(config.logging.topics, Some(vec!["substrate".to_string(), "access".to_string()]));
+        assert_eq!(config.logging.sinks, Some(vec!["json".to_string()]));
+        assert_eq!(config.cors.origins, Some(vec!["https://a.example".to_string()]));
+    }
+
+    #[test]
+    fn env_ignores_other_variables_but_rejects_unknown_keys() {
+        let config = overlay("", &[("SUBSTRATE_NOPE_PORT", "1"), ("SUBSTRATE", "x"), ("PORT", "1")]).unwrap();
+        assert_eq!(config.server.port, None);
+
+        assert!(overlay("", &[("SUBSTRATE_SERVER_PROT", "8080")]).is_err());
+        assert!(overlay("", &[("SUBSTRATE_SERVER_PORT", "eighty")]).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Result};
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::http::{Method, StatusCode};
+use warp::Reply;
+
+use crate::config_file::CorsSection;
+
+/// Methods preflights may ask for when none are configured
+const DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+/// Which cross-origin requests browsers may make to an applet
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    pub origins: Vec<String>, // Allowed origins, or `*` for any; none turns CORS off
+    pub methods: Vec<String>, // Methods preflights may ask for
+    pub headers: Vec<String>, // Request headers preflights may ask for, or `*` for any
+    pub credentials: bool,    // Let browsers send credentials, to origins listed by name
+    pub max_age: u64,         // Seconds browsers may cache a preflight (0 = not sent)
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            origins: Vec::new(),
+            methods: DEFAULT_METHODS.iter().map(|method| method.to_string()).collect(),
+            headers: Vec::new(),
+            credentials: false,
+            max_age: 0,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Build the global policy from the `[cors]` section
+    pub fn resolve(section: &CorsSection) -> Result<Self> {
+        validate(section)?;
+        Ok(CorsPolicy::default().with(section))
+    }
+
+    /// This policy with the settings in `overrides` replaced; overrides are
+    /// validated when they are loaded
+    pub fn with(&self, overrides: &CorsSection) -> CorsPolicy {
+        CorsPolicy {
+            origins: overrides.origins.clone().unwrap_or_else(|| self.origins.clone()),
+            methods: overrides
+                .methods
+                .as_ref()
+                .map(|methods| methods.iter().map(|method| method.to_ascii_uppercase()).collect())
+                .unwrap_or_else(|| self.methods.clone()),
+            headers: overrides
+                .headers
+                .as_ref()
+                .map(|headers| headers.iter().map(|name| name.to_ascii_lowercase()).collect())
+                .unwrap_or_else(|| self.headers.clone()),
+            credentials: overrides.credentials.unwrap_or(self.credentials),
+            max_age: overrides.max_age.unwrap_or(self.max_age),
+        }
+    }
+
+    pub fn to_section(&self) -> CorsSection {
+        CorsSection {
+            origins: Some(self.origins.clone()),
+            methods: Some(self.methods.clone()),
+            headers: Some(self.headers.clone()),
+            credentials: Some(self.credentials),
+            max_age: Some(self.max_age),
+        }
+    }
+
+    /// Answer a preflight request: 204 with the allowed methods and headers,
+    /// or 403 when the origin, method or headers are not allowed. `None`
+    /// when the request is not a preflight or CORS is off, so it goes to
+    /// the guest like any other.
+    pub fn preflight(&self, method: &Method, headers: &HeaderMap) -> Option<warp::reply::Response> {
+        if self.origins.is_empty() || method != Method::OPTIONS {
+            return None;
+        }
+        let origin = headers.get(header::ORIGIN)?;
+        let requested_method = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+
+        let requested_headers: Vec<String> = headers
+            .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let method_allowed = requested_method
+            .to_str()
+            .is_ok_and(|requested| self.methods.iter().any(|method| method == requested));
+        let headers_allowed = self.headers.iter().any(|name| name == "*")
+            || requested_headers.iter().all(|requested| self.headers.contains(requested));
+        let explicit = match self.matches(origin) {
+            Some(explicit) if method_allowed && headers_allowed => explicit,
+            _ => return Some(forbidden()),
+        };
+
+        let mut reply = warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT).into_response();
+        let response_headers = reply.headers_mut();
+        response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        if let Ok(methods) = HeaderValue::from_str(&self.methods.join(", ")) {
+            response_headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if !requested_headers.is_empty() {
+            if let Ok(names) = HeaderValue::from_str(&requested_headers.join(", ")) {
+                response_headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, names);
+            }
+        }
+        if self.credentials && explicit {
+            response_headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if self.max_age > 0 {
+            response_headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age));
+        }
+        response_headers.insert(
+            header::VARY,
+            HeaderValue::from_static("origin, access-control-request-method, access-control-request-headers"),
+        );
+        Some(reply)
+    }
+
+    /// Let the browser hand the response to a page from an allowed origin
+    pub fn apply(&self, origin: Option<&HeaderValue>, reply: &mut warp::reply::Response) {
+        if self.origins.is_empty() {
+            return;
+        }
+        reply.headers_mut().append(header::VARY, HeaderValue::from_static("origin"));
+        let Some(origin) = origin else {
+            return;
+        };
+        let Some(explicit) = self.matches(origin) else {
+            return;
+        };
+        reply.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        if self.credentials && explicit {
+            reply
+                .headers_mut()
+                .insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+
+    /// Whether a page may open a WebSocket to the applet. Browsers do not
+    /// apply CORS to upgrades, so the origin is checked here; clients that
+    /// send no `Origin` and applets without CORS are let through.
+    pub fn allows_socket(&self, origin: Option<&HeaderValue>) -> bool {
+        self.origins.is_empty() || origin.is_none_or(|origin| self.matches(origin).is_some())
+    }
+
+    /// Whether the origin is allowed, and if so whether it is listed by
+    /// name; origins allowed only through `*` never get credentials
+    fn matches(&self, origin: &HeaderValue) -> Option<bool> {
+        let origin = origin.to_str().ok()?;
+        if self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            return Some(true);
+        }
+        self.origins.iter().any(|allowed| allowed == "*").then_some(false)
+    }
+}
+
+/// The 403 for a cross-origin request the policy does not allow
+pub fn forbidden() -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": "Cross-origin request not allowed" })),
+        StatusCode::FORBIDDEN,
+    )
+    .into_response()
+}
+
+/// Check the settings of a `[cors]` section or an applet's `cors` table
+pub fn validate(section: &CorsSection) -> Result<()> {
+    for origin in section.origins.iter().flatten() {
+        if origin != "*" && !(origin.starts_with("http://") || origin.starts_with("https://")) {
+            return Err(anyhow!("Invalid CORS origin '{}': expected `*` or a scheme and host", origin));
+        }
+    }
+    for method in section.methods.iter().flatten() {
+        Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| anyhow!("Invalid CORS method '{}'", method))?;
+    }
+    for name in section.headers.iter().flatten() {
+        if name != "*" {
+            header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow!("Invalid CORS header '{}'", name))?;
+        }
+    }
+    let any_origin = section.origins.iter().flatten().any(|origin| origin == "*");
+    if any_origin && section.credentials == Some(true) {
+        return Err(anyhow!("CORS credentials are only allowed for origins listed by name, not `*`"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> CorsPolicy {
+        CorsPolicy {
+            origins: vec!["https://app.example".to_string(), "*".to_string()],
+            methods: vec!["GET".to_string(), "PUT".to_string()],
+            headers: vec!["content-type".to_string()],
+            credentials: true,
+            max_age: 600,
+        }
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> HeaderMap {
+        let mut request = HeaderMap::new();
+        request.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        request.insert(header::ACCESS_CONTROL_REQUEST_METHOD, HeaderValue::from_str(method).unwrap());
+        if !headers.is_empty() {
+            request.insert(header::ACCESS_CONTROL_REQUEST_HEADERS, HeaderValue::from_str(headers).unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn preflights_list_what_is_allowed() {
+        let request = preflight("https://app.example", "PUT", "Content-Type");
+        let reply = policy().preflight(&Method::OPTIONS, &request).unwrap();
+        assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+        let headers = reply.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        // Origins allowed only through `*` never get credentials
+        let reply = policy().preflight(&Method::OPTIONS, &preflight("https://other.example", "GET", "")).unwrap();
+        assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+        assert!(!reply.headers().contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+
+    #[test]
+    fn preflights_outside_the_policy_are_refused() {
+        let named = CorsPolicy { origins: vec!["https://app.example".to_string()], ..policy() };
+        for request in [
+            preflight("https://other.example", "GET", ""),
+            preflight("https://app.example", "DELETE", ""),
+            preflight("https://app.example", "GET", "x-secret"),
+        ] {
+            assert_eq!(named.preflight(&Method::OPTIONS, &request).unwrap().status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn other_requests_are_not_preflights() {
+        let request = preflight("https://app.example", "GET", "");
+        assert!(policy().preflight(&Method::GET, &request).is_none());
+        assert!(CorsPolicy::default().preflight(&Method::OPTIONS, &request).is_none());
+
+        let mut without_method = request.clone();
+        without_method.remove(header::ACCESS_CONTROL_REQUEST_METHOD);
+        assert!(policy().preflight(&Method::OPTIONS, &without_method).is_none());
+    }
+
+    #[test]
+    fn sockets_are_opened_from_allowed_origins() {
+        let named = CorsPolicy { origins: vec!["https://app.example".to_string()], ..policy() };
+        assert!(named.allows_socket(Some(&HeaderValue::from_static("https://app.example"))));
+        assert!(named.allows_socket(None));
+        assert!(!named.allows_socket(Some(&HeaderValue::from_static("https://other.example"))));
+        assert!(policy().allows_socket(Some(&HeaderValue::from_static("https://other.example"))));
+        assert!(CorsPolicy::default().allows_socket(Some(&HeaderValue::from_static("https://other.example"))));
+    }
+
+    #[test]
+    fn responses_name_allowed_origins() {
+        let named = CorsPolicy { origins: vec!["https://app.example".to_string()], ..policy() };
+        let mut reply = warp::reply().into_response();
+        named.apply(Some(&HeaderValue::from_static("https://app.example")), &mut reply);
+        assert_eq!(reply.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example");
+        assert_eq!(reply.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(reply.headers()[header::VARY], "origin");
+
+        let mut reply = warp::reply().into_response();
+        named.apply(Some(&HeaderValue::from_static("https://other.example")), &mut reply);
+        assert!(!reply.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut reply = warp::reply().into_response();
+        CorsPolicy::default().apply(Some(&HeaderValue::from_static("https://app.example")), &mut reply);
+        assert!(reply.headers().is_empty());
+    }
+
+    #[test]
+    fn sections_are_validated() {
+        let section = |origins: &[&str], credentials| CorsSection {
+            origins: Some(origins.iter().map(|origin| origin.to_string()).collect()),
+            credentials: Some(credentials),
+            ..Default::default()
+        };
+        assert!(validate(&section(&["https://app.example"], true)).is_ok());
+        assert!(validate(&section(&["app.example"], false)).is_err());
+        assert!(validate(&section(&["*"], true)).is_err());
+        assert!(validate(&CorsSection { methods: Some(vec!["GE T".to_string()]), ..Default::default() }).is_err());
+    }
+}
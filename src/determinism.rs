@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use cap_rand::rngs::StdRng;
+use cap_rand::{RngCore, SeedableRng};
+use wasi_common::clocks::{WasiClocks, WasiMonotonicClock, WasiSystemClock};
+
+use anyhow::{anyhow, Result};
+use warp::http::HeaderMap;
+
+use crate::trace::SpanContext;
+
+/// Request header that asks for a deterministic run with the given seed,
+/// honoured for applets deployed with `allow_seed_header`; the seed used is
+/// echoed back under the same name
+pub const SEED_HEADER: &str = "x-substrate-seed";
+
+/// Wall-clock time the virtual system clock starts at (2020-01-01T00:00:00Z)
+const VIRTUAL_EPOCH_SECS: u64 = 1_577_836_800;
+
+/// How far the virtual clocks advance each time they are read
+const TICK: Duration = Duration::from_millis(1);
+
+/// Random source for a deterministic run
+pub fn rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// WASI `random_get` source for a deterministic run
+pub fn wasi_random(seed: u64) -> Box<dyn RngCore + Send + Sync> {
+    Box::new(rng(seed))
+}
+
+/// WASI clocks for a deterministic run: both start at a fixed point and
+/// advance by one tick per read, independent of real time
+pub fn wasi_clocks() -> WasiClocks {
+    WasiClocks::new()
+        .with_system(VirtualSystemClock { reads: AtomicU64::new(0) })
+        .with_monotonic(VirtualMonotonicClock { base: Instant::now(), reads: AtomicU64::new(0) })
+}
+
+/// The seed a request asks for in its `x-substrate-seed` header, if any
+pub fn requested_seed(headers: &HeaderMap) -> Result<Option<u64>> {
+    let Some(value) = headers.get(SEED_HEADER) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Some)
+        .ok_or_else(|| anyhow!("Invalid {} header: expected an unsigned integer", SEED_HEADER))
+}
+
+/// A fresh seed for applets that are deterministic without a seed being asked for
+pub fn new_seed() -> u64 {
+    let bytes = uuid::Uuid::new_v4();
+    let mut seed = [0u8; 8];
+    seed.copy_from_slice(&bytes.as_bytes()[..8]);
+    u64::from_le_bytes(seed)
+}
+
+struct VirtualSystemClock {
+    reads: AtomicU64,
+}
+
+impl WasiSystemClock for VirtualSystemClock {
+    fn resolution(&self) -> cap_std::time::Duration {
+        TICK
+    }
+
+    fn now(&self, _precision: cap_std::time::Duration) -> cap_std::time::SystemTime {
+        let reads = self.reads.fetch_add(1, Ordering::Relaxed) as u32;
+        cap_std::time::SystemTime::from_std(UNIX_EPOCH + Duration::from_secs(VIRTUAL_EPOCH_SECS) + TICK * reads)
+    }
+}
+
+struct VirtualMonotonicClock {
+    base: Instant, // Only differences from the first read are visible to the guest
+    reads: AtomicU64,
+}
+
+impl WasiMonotonicClock for VirtualMonotonicClock {
+    fn resolution(&self) -> cap_std::time::Duration {
+        TICK
+    }
+
+    fn now(&self, _precision: cap_std::time::Duration) -> cap_std::time::Instant {
+        let reads = self.reads.fetch_add(1, Ordering::Relaxed) as u32;
+        cap_std::time::Instant::from_std(self.base + TICK * reads)
+    }
+}
+
+/// A `traceparent` drawn from the seeded source, handed to the guest in
+/// place of the live one so its output does not depend on trace IDs
+pub fn traceparent(rng: &mut StdRng, sampled: bool) -> String {
+    let mut context = SpanContext { trace_id: [0; 16], span_id: [0; 8], sampled };
+    rng.fill_bytes(&mut context.trace_id);
+    rng.fill_bytes(&mut context.span_id);
+    context.to_traceparent()
+}
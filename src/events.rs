@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::config;
+
+/// Longest channel name accepted
+const MAX_CHANNEL_LEN: usize = 128;
+
+/// Channels kept before ones with no events and no subscribers are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Events queued for each subscriber before a slow one falls behind
+const SUBSCRIBER_BUFFER: usize = 256;
+
+/// An event published to a channel
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,               // Counts up from 1 on each channel
+    pub event: Option<String>, // Event type, for clients listening by name
+    pub data: String,
+}
+
+struct Channel {
+    last_id: u64,
+    retained: VecDeque<Arc<Event>>, // Most recent events, for clients that reconnect
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+/// Channels by applet and name; each applet publishes to its own
+static CHANNELS: OnceLock<Mutex<HashMap<(Uuid, String), Channel>>> = OnceLock::new();
+
+fn channels() -> &'static Mutex<HashMap<(Uuid, String), Channel>> {
+    CHANNELS.get_or_init(Default::default)
+}
+
+/// Whether `name` can name a channel: letters, digits, `-`, `_` and `.`
+pub fn valid_channel(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CHANNEL_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Send an event to the channel's subscribers and keep it for ones that
+/// reconnect; returns its ID
+pub fn publish(applet: Uuid, channel: &str, event: Option<String>, data: String) -> u64 {
+    publish_retaining(applet, channel, event, data, config::global_config().events_retain)
+}
+
+/// `publish`, keeping up to `retain` events on the channel
+fn publish_retaining(applet: Uuid, channel: &str, event: Option<String>, data: String, retain: usize) -> u64 {
+    let mut channels = channels().lock().unwrap();
+    let channel = entry(&mut channels, applet, channel);
+
+    channel.last_id += 1;
+    let event = Arc::new(Event { id: channel.last_id, event, data });
+    channel.retained.push_back(event.clone());
+    while channel.retained.len() > retain {
+        channel.retained.pop_front();
+    }
+    let _ = channel.sender.send(event); // Fails only when nobody is subscribed
+    channel.last_id
+}
+
+/// Subscribe to a channel. With the ID of the last event a client saw, the
+/// retained events after it are returned to be sent first. An ID the
+/// channel has not reached yet dates from before a restart, and nothing is
+/// replayed for it: the client cannot tell which of the retained events
+/// it has seen, so it is treated as caught up.
+pub fn subscribe(
+    applet: Uuid,
+    channel: &str,
+    last_event_id: Option<u64>,
+) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+    let mut channels = channels().lock().unwrap();
+    let channel = entry(&mut channels, applet, channel);
+
+    let missed = match last_event_id {
+        Some(last) if last <= channel.last_id => {
+            channel.retained.iter().filter(|event| event.id > last).cloned().collect()
+        }
+        Some(_) | None => Vec::new(),
+    };
+    (missed, channel.sender.subscribe())
+}
+
+fn entry<'a>(channels: &'a mut HashMap<(Uuid, String), Channel>, applet: Uuid, name: &str) -> &'a mut Channel {
+    let key = (applet, name.to_string());
+    if channels.len() >= PRUNE_THRESHOLD && !channels.contains_key(&key) {
+        channels.retain(|_, channel| !channel.retained.is_empty() || channel.sender.receiver_count() > 0);
+    }
+    channels.entry(key).or_insert_with(|| Channel {
+        last_id: 0,
+        retained: VecDeque::new(),
+        sender: broadcast::channel(SUBSCRIBER_BUFFER).0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[Arc<Event>]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[test]
+    fn subscribers_catch_up_from_the_last_event_id() {
+        let applet = Uuid::new_v4();
+        for i in 1..=5 {
+            publish_retaining(applet, "news", None, format!("event {}", i), 3);
+        }
+        assert!(subscribe(applet, "news", None).0.is_empty());
+        assert_eq!(ids(&subscribe(applet, "news", Some(3)).0), [4, 5]);
+        assert!(subscribe(applet, "news", Some(5)).0.is_empty());
+
+        // Events older than the retained ones are gone
+        assert_eq!(ids(&subscribe(applet, "news", Some(1)).0), [3, 4, 5]);
+
+        // An ID the channel has not reached dates from before a restart
+        assert!(subscribe(applet, "news", Some(99)).0.is_empty());
+    }
+
+    #[test]
+    fn subscribers_get_new_events_on_their_applet_channel() {
+        let applet = Uuid::new_v4();
+        let (_, mut news) = subscribe(applet, "news", None);
+        let (_, mut other_channel) = subscribe(applet, "sport", None);
+        let (_, mut other_applet) = subscribe(Uuid::new_v4(), "news", None);
+
+        let id = publish_retaining(applet, "news", Some("update".to_string()), "hello".to_string(), 10);
+        let event = news.try_recv().unwrap();
+        assert_eq!((event.id, event.event.as_deref(), event.data.as_str()), (id, Some("update"), "hello"));
+        assert!(other_channel.try_recv().is_err());
+        assert!(other_applet.try_recv().is_err());
+    }
+
+    #[test]
+    fn channel_names() {
+        assert!(valid_channel("orders.v2-eu_west"));
+        assert!(!valid_channel(""));
+        assert!(!valid_channel("a/b"));
+        assert!(!valid_channel(&"a".repeat(MAX_CHANNEL_LEN + 1)));
+    }
+}
@@ -1,48 +1,211 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
 use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use serde_json::Value;
+use tokio::sync::mpsc;
 use wasmtime::*;
-use wasmtime_wasi::{add_to_linker, WasiCtx, WasiCtxBuilder};
+use wasi_common::table::Table;
+use wasmtime_wasi::sync::{ambient_authority, clocks_ctx, random_ctx, sched_ctx, stdio, Dir};
+use wasmtime_wasi::{add_to_linker, WasiCtx};
+use warp::ws::Message;
 
 // Import the host module
-use crate::host;
+use crate::applet_store::{AppletSettings, WasiSettings};
+use crate::config::Limits;
+use crate::determinism;
+use crate::host::{self, BodyReader, HostState, ResponseWriter, Socket};
+use crate::trace;
+use crate::types::{HttpRequest, HttpResponse};
+
+/// Resources used by a single execution
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecutionStats {
+    pub fuel_consumed: u64, // Fuel burnt by the guest
+    pub memory_bytes: u64,  // Size of the guest's linear memory when it returned
+    pub instantiate_time: Duration, // Time taken to instantiate the module
+}
+
+/// Engine shared by every executor, so one epoch tick reaches all guests
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+
+/// Executions currently running
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+fn engine() -> Result<&'static Engine> {
+    if let Some(engine) = ENGINE.get() {
+        return Ok(engine);
+    }
+
+    // Fuel is metered so the cost of each invocation can be reported.
+    // NaNs are canonicalized so any invocation can run deterministically.
+    // Epochs let shutdown interrupt guests that are still running.
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    config.cranelift_nan_canonicalization(true);
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    Ok(ENGINE.get_or_init(|| engine))
+}
+
+/// Identifies the engine configuration; compiled modules are only
+/// interchangeable between hosts with the same fingerprint
+pub fn engine_fingerprint() -> Result<String> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    engine()?.precompile_compatibility_hash().hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Engine features every applet runs with
+pub const ENGINE_FEATURES: [&str; 5] = ["wasi", "fuel", "epoch_interruption", "nan_canonicalization", "wat"];
+
+/// Trap every guest that is running now; used when the shutdown grace
+/// period runs out
+pub fn interrupt_all() {
+    if let Some(engine) = ENGINE.get() {
+        engine.increment_epoch();
+    }
+}
+
+/// Number of executions currently running
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+/// Counts an execution as in flight while alive
+struct InFlight;
+
+impl InFlight {
+    fn enter() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub struct Executor {
     engine: Engine,
-    linker: Linker<WasiCtx>,
+    linker: Linker<HostState>,
+    module: Module,
+    wasi: WasiSettings,
 }
 
 impl Executor {
-    /// Create a new Executor with reusable environment
-    pub fn new() -> Result<Self> {
-        let engine = Engine::default();
-        let mut linker = Linker::new(&engine);
+    /// Create a new Executor with reusable environment and a compiled module.
+    /// Only the host functions allowed by the applet's capabilities are linked.
+    pub fn new(wasm_binary: &[u8], settings: &AppletSettings) -> Result<Self> {
+        let engine = engine()?.clone();
+        let linker = Self::linker(&engine, settings)?;
+
+        // Compile the module once so it can be reused across executions
+        let module = Module::new(&engine, wasm_binary)?;
+
+        Ok(Self { engine, linker, module, wasi: settings.wasi.clone() })
+    }
+
+    /// Linker providing WASI and the host functions the applet may use
+    fn linker(engine: &Engine, settings: &AppletSettings) -> Result<Linker<HostState>> {
+        let mut linker = Linker::new(engine);
 
         // Add WASI functions to the linker
-        add_to_linker(&mut linker, |ctx| ctx)?; // Modified closure
+        add_to_linker(&mut linker, |state: &mut HostState| &mut state.wasi)?;
 
-        // Add the custom `log` function to the linker
-        linker.func_wrap("env", "log", host::Host::log)?;
+        // Add the custom host functions the applet is allowed to use
+        let allowed = |name: &str| {
+            settings
+                .capabilities
+                .as_ref()
+                .is_none_or(|capabilities| capabilities.iter().any(|c| c == name))
+        };
+        if allowed("log") {
+            linker.func_wrap("env", "log", host::Host::log)?;
+        }
+        if allowed("log_event") {
+            linker.func_wrap("env", "log_event", host::Host::log_event)?;
+        }
+        if allowed("traceparent") {
+            linker.func_wrap("env", "traceparent", host::Host::traceparent)?;
+        }
+        if allowed("request_id") {
+            linker.func_wrap("env", "request_id", host::Host::request_id)?;
+        }
+        if allowed("principal") {
+            linker.func_wrap("env", "principal", host::Host::principal)?;
+        }
+        if allowed("read_body") {
+            linker.func_wrap("env", "read_body", host::Host::read_body)?;
+        }
+        if allowed("response_start") {
+            linker.func_wrap("env", "response_start", host::Host::response_start)?;
+        }
+        if allowed("response_write") {
+            linker.func_wrap("env", "response_write", host::Host::response_write)?;
+        }
+        if allowed("response_trailers") {
+            linker.func_wrap("env", "response_trailers", host::Host::response_trailers)?;
+        }
+        if allowed("ws_message") {
+            linker.func_wrap("env", "ws_message", host::Host::ws_message)?;
+        }
+        if allowed("ws_send") {
+            linker.func_wrap("env", "ws_send", host::Host::ws_send)?;
+        }
+        if allowed("ws_close") {
+            linker.func_wrap("env", "ws_close", host::Host::ws_close)?;
+        }
+        if allowed("publish") {
+            linker.func_wrap("env", "publish", host::Host::publish)?;
+        }
+        Ok(linker)
+    }
 
-        Ok(Self { engine, linker })
+    /// Check that every import resolves against the host and that the
+    /// module exports the `run` function, or `on_message` for WebSocket applets
+    pub fn validate(&self) -> Result<()> {
+        self.linker.instantiate_pre(&self.module)?;
+        match (self.module.get_export("run"), self.module.get_export("on_message")) {
+            (Some(ExternType::Func(_)), _) | (None, Some(ExternType::Func(_))) => Ok(()),
+            (Some(_), _) => Err(anyhow!("Export `run` is not a function")),
+            (None, Some(_)) => Err(anyhow!("Export `on_message` is not a function")),
+            (None, None) => Err(anyhow!("Function `run` not exported")),
+        }
     }
 
-    /// Execute a WebAssembly module with the specified function and arguments
+    /// The compiled module
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Execute the compiled module's `run` function with the given arguments
+    /// under the given resource limits, serving `request` to its host calls.
+    /// With a seed, clocks, randomness and host nondeterminism come from a
+    /// virtual source seeded with it. Also returns the response the guest
+    /// wrote through the `response_*` host calls, if it started one.
     pub fn execute(
         &self,
-        wasm_binary: &[u8],
         args: &[Val],
-    ) -> Result<Value> {
-        // Create a new WASI context
-        let wasi_ctx = WasiCtxBuilder::new().build();
-
-        // Create a new Store for this execution
-        let mut store = Store::new(&self.engine, wasi_ctx);
-
-        // Compile the module
-        let module = Module::new(&self.engine, wasm_binary)?;
+        limits: Limits,
+        seed: Option<u64>,
+        request: &HttpRequest,
+    ) -> Result<(Value, ExecutionStats, Option<HttpResponse>)> {
+        let _in_flight = InFlight::enter();
+        let mut store = self.store(&limits, seed, request)?;
 
         // Instantiate the module
-        let instance = self.linker.instantiate(&mut store, &module)?;
+        let instantiate_started = Instant::now();
+        let instance = {
+            let _span = trace::start("instantiate");
+            self.linker.instantiate(&mut store, &self.module)?
+        };
+        let instantiate_time = instantiate_started.elapsed();
 
         // Get the function from the module
         let func = instance.get_func(&mut store, "run")
@@ -52,13 +215,168 @@ impl Executor {
         let mut results = vec![Val::null(); func.ty(&store).results().len()];
 
         // Call the function with the provided arguments
-        func.call(&mut store, args, &mut results)?;
+        {
+            let _span = trace::start("guest.execute");
+            if let Err(e) = func.call(&mut store, args, &mut results) {
+                store.data_mut().response.abort();
+                return Err(e);
+            }
+        }
+
+        let stats = ExecutionStats {
+            fuel_consumed: store.fuel_consumed().unwrap_or(0),
+            memory_bytes: instance
+                .get_memory(&mut store, "memory")
+                .map(|memory| memory.data_size(&store) as u64)
+                .unwrap_or(0),
+            instantiate_time,
+        };
+        let response = store.into_data().response.finish();
 
         // Assuming the function returns a single i32 result
-        if let Some(Val::I32(result)) = results.get(0) {
-            Ok(serde_json::json!({ "result": result }))
+        if let Some(Val::I32(result)) = results.first() {
+            Ok((serde_json::json!({ "result": result }), stats, response))
         } else {
-            Ok(serde_json::json!({ "result": null }))
+            Ok((serde_json::json!({ "result": null }), stats, response))
+        }
+    }
+
+    /// Instantiate the module for a WebSocket connection. The instance, and
+    /// with it the guest's memory, lives as long as the connection; frames
+    /// the guest sends go to `frames`.
+    pub fn open_session(
+        &self,
+        limits: Limits,
+        seed: Option<u64>,
+        request: &HttpRequest,
+        frames: mpsc::Sender<Message>,
+    ) -> Result<Session> {
+        let mut store = self.store(&limits, seed, request)?;
+        store.data_mut().socket = Some(Socket::new(frames, limits.client_wait()));
+        let instance = {
+            let _span = trace::start("instantiate");
+            self.linker.instantiate(&mut store, &self.module)?
+        };
+        if instance.get_func(&mut store, "on_message").is_none() {
+            return Err(anyhow!("Function `on_message` not found"));
+        }
+        Ok(Session { store, instance, fuel: limits.fuel })
+    }
+
+    /// A new Store holding the state one instance runs with
+    fn store(&self, limits: &Limits, seed: Option<u64>, request: &HttpRequest) -> Result<Store<HostState>> {
+        // Create a new WASI context
+        let wasi_ctx = self.wasi_ctx(seed)?;
+
+        // Cap linear memory growth when a limit is configured
+        let mut store_limits = StoreLimitsBuilder::new();
+        if limits.max_memory_bytes > 0 {
+            store_limits = store_limits.memory_size(limits.max_memory_bytes);
+        }
+
+        // Create a new Store for this execution
+        let state = HostState {
+            wasi: wasi_ctx,
+            limits: store_limits.build(),
+            rng: seed.map(|seed| determinism::rng(seed.wrapping_add(1))),
+            principal: request.principal.as_ref().map(serde_json::to_string).transpose()?,
+            body: BodyReader::new(request.body.clone(), request.body_stream.clone()),
+            response: ResponseWriter::new(request.response_sink.clone(), seed, limits.client_wait()),
+            socket: None,
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(1);
+        store.add_fuel(if limits.fuel > 0 { limits.fuel } else { u64::MAX })?;
+        Ok(store)
+    }
+
+    /// Build the WASI context described by the applet's settings
+    fn wasi_ctx(&self, seed: Option<u64>) -> Result<WasiCtx> {
+        let (random, clocks) = match seed {
+            Some(seed) => (determinism::wasi_random(seed), determinism::wasi_clocks()),
+            None => (random_ctx(), clocks_ctx()),
+        };
+        let mut ctx = WasiCtx::new(random, clocks, sched_ctx(), Table::new());
+
+        for arg in &self.wasi.args {
+            ctx.push_arg(arg)?;
         }
+        for (key, value) in &self.wasi.env {
+            ctx.push_env(key, value)?;
+        }
+        if self.wasi.inherit_stdout {
+            ctx.set_stdout(Box::new(stdio::stdout()));
+        }
+        if self.wasi.inherit_stderr {
+            ctx.set_stderr(Box::new(stdio::stderr()));
+        }
+        for dir in &self.wasi.dirs {
+            let host_dir = Dir::open_ambient_dir(&dir.host, ambient_authority())
+                .map_err(|e| anyhow!("Failed to open '{}': {}", dir.host.display(), e))?;
+            ctx.push_preopened_dir(Box::new(wasmtime_wasi::sync::dir::Dir::from_cap_std(host_dir)), &dir.guest)?;
+        }
+        Ok(ctx)
+    }
+}
+
+/// A guest instance serving one WebSocket connection. Each callback gets
+/// the full fuel limit; memory persists between them.
+pub struct Session {
+    store: Store<HostState>,
+    instance: Instance,
+    fuel: u64, // Fuel available to each callback (0 = unlimited)
+}
+
+impl Session {
+    /// Call the guest's `on_open` export, if it has one
+    pub fn on_open(&mut self) -> Result<ExecutionStats> {
+        self.call("on_open", &[])
+    }
+
+    /// Call the guest's `on_message` export with the message's length and
+    /// whether it is text; the guest reads it through `ws_message`
+    pub fn on_message(&mut self, message: Bytes, text: bool) -> Result<ExecutionStats> {
+        let args = [Val::I32(message.len() as i32), Val::I32(text as i32)];
+        if let Some(socket) = self.store.data_mut().socket.as_mut() {
+            socket.set_message(message);
+        }
+        self.call("on_message", &args)
+    }
+
+    /// Call the guest's `on_close` export, if it has one, with the close code
+    pub fn on_close(&mut self, code: u16) -> Result<ExecutionStats> {
+        self.call("on_close", &[Val::I32(code as i32)])
+    }
+
+    fn call(&mut self, name: &str, args: &[Val]) -> Result<ExecutionStats> {
+        let _in_flight = InFlight::enter();
+        let Some(func) = self.instance.get_func(&mut self.store, name) else {
+            return Ok(ExecutionStats::default());
+        };
+
+        // Top the fuel back up to the limit and re-arm the shutdown interrupt
+        if self.fuel > 0 {
+            let remaining = self.store.consume_fuel(0)?;
+            self.store.add_fuel(self.fuel.saturating_sub(remaining))?;
+        }
+        self.store.set_epoch_deadline(1);
+        let consumed_before = self.store.fuel_consumed().unwrap_or(0);
+
+        let mut results = vec![Val::null(); func.ty(&self.store).results().len()];
+        {
+            let _span = trace::start(&format!("guest.{}", name));
+            func.call(&mut self.store, args, &mut results)?;
+        }
+
+        Ok(ExecutionStats {
+            fuel_consumed: self.store.fuel_consumed().unwrap_or(0) - consumed_before,
+            memory_bytes: self
+                .instance
+                .get_memory(&mut self.store, "memory")
+                .map(|memory| memory.data_size(&self.store) as u64)
+                .unwrap_or(0),
+            instantiate_time: Duration::ZERO,
+        })
     }
 }
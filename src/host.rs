@@ -1,26 +1,224 @@
 use anyhow::{anyhow, Result};
-use wasmtime::{Caller, Memory, Extern};
+use serde_json::Value;
+use wasmtime::{Caller, Memory, Extern, StoreLimits};
 use wasmtime_wasi::WasiCtx;
 
 // Import your log module
-use crate::log;
+use crate::log::{self, Level};
+use bytes::Bytes;
+use cap_rand::rngs::StdRng;
+
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, StatusCode};
+use warp::ws::Message;
+
+use crate::types::{BodyError, BodyStream, HttpResponse, ResponseFrame, ResponseSink};
+use crate::{determinism, events, record, trace};
+
+/// Host functions applets may import from `env`; these are also the
+/// capability names used in deployment manifests
+pub const HOST_FUNCTIONS: [&str; 13] = [
+    "log",
+    "log_event",
+    "traceparent",
+    "request_id",
+    "principal",
+    "read_body",
+    "response_start",
+    "response_write",
+    "response_trailers",
+    "ws_message",
+    "ws_send",
+    "ws_close",
+    "publish",
+];
+
+/// Per-invocation state held in each `Store`
+pub struct HostState {
+    pub wasi: WasiCtx,        // WASI context for the guest
+    pub limits: StoreLimits,  // Memory limits enforced by the engine
+    pub rng: Option<StdRng>,  // Seeded source for host nondeterminism in deterministic mode
+    pub principal: Option<String>, // Verified caller as JSON, for the `principal` host call
+    pub body: BodyReader,     // Request body, for the `read_body` host call
+    pub response: ResponseWriter, // Response written in parts through the `response_*` host calls
+    pub socket: Option<Socket>,   // WebSocket connection, for sessions serving one
+}
+
+/// The request body as the guest reads it through `read_body`: the part
+/// received up front, then whatever is still streaming in
+pub struct BodyReader {
+    pending: Bytes,
+    stream: Option<BodyStream>,
+}
+
+impl BodyReader {
+    pub fn new(body: Bytes, stream: Option<BodyStream>) -> Self {
+        BodyReader { pending: body, stream }
+    }
+
+    /// Up to `max` bytes of the body, waiting for more to arrive when
+    /// needed; `None` at the end
+    fn read(&mut self, max: usize) -> std::result::Result<Option<Bytes>, BodyError> {
+        while self.pending.is_empty() {
+            let Some(stream) = &self.stream else {
+                return Ok(None);
+            };
+            match stream.next_chunk()? {
+                Some(chunk) => self.pending = chunk,
+                None => self.stream = None,
+            }
+        }
+        Ok(Some(self.pending.split_to(max.min(self.pending.len()))))
+    }
+}
+
+/// A response the guest writes in parts: sent on as it is written when the
+/// request came over a connection, and collected otherwise
+pub struct ResponseWriter {
+    sink: Option<ResponseSink>,
+    seed: Option<u64>,                           // Echoed back like on buffered responses
+    timeout: Option<Duration>,                   // Longest wait for a client that is behind
+    head: Option<(u16, HeaderMap)>,              // Set once the response has started
+    frames: Option<mpsc::Sender<ResponseFrame>>, // Set while streaming to the client
+    body: Vec<u8>,                               // Collected when not streaming, or for a recording
+    keep_body: bool,
+    ended: bool, // Trailers were written
+}
+
+impl ResponseWriter {
+    pub fn new(sink: Option<ResponseSink>, seed: Option<u64>, timeout: Option<Duration>) -> Self {
+        let keep_body = sink.is_none() || record::is_recording();
+        ResponseWriter { sink, seed, timeout, head: None, frames: None, body: Vec::new(), keep_body, ended: false }
+    }
+
+    fn start(&mut self, status_code: u16, mut headers: HeaderMap) -> Result<()> {
+        if self.head.is_some() {
+            return Err(anyhow!("Response already started"));
+        }
+        if let Some(seed) = self.seed {
+            headers.insert(determinism::SEED_HEADER, HeaderValue::from(seed));
+        }
+        if let Some(sink) = &self.sink {
+            let frames = sink.start(status_code, headers.clone()).ok_or_else(|| anyhow!("Client disconnected"))?;
+            self.frames = Some(frames);
+        }
+        self.head = Some((status_code, headers));
+        Ok(())
+    }
+
+    fn write(&mut self, chunk: Bytes) -> Result<()> {
+        if self.head.is_none() {
+            self.start(200, HeaderMap::new())?;
+        }
+        if self.ended {
+            return Err(anyhow!("Response already ended"));
+        }
+        if self.keep_body {
+            self.body.extend_from_slice(&chunk);
+        }
+        self.send(ResponseFrame::Data(chunk))
+    }
+
+    /// End the response, sending the trailers when the client can receive
+    /// them; returns whether it can
+    fn end(&mut self, trailers: HeaderMap) -> Result<bool> {
+        if self.head.is_none() {
+            self.start(200, HeaderMap::new())?;
+        }
+        if self.ended {
+            return Err(anyhow!("Response already ended"));
+        }
+        self.ended = true;
+        if !self.sink.as_ref().is_some_and(ResponseSink::trailers) {
+            return Ok(false);
+        }
+        self.send(ResponseFrame::Trailers(trailers))?;
+        Ok(true)
+    }
+
+    fn send(&self, frame: ResponseFrame) -> Result<()> {
+        match &self.frames {
+            Some(frames) => send_within(frames, frame, self.timeout),
+            None => Ok(()),
+        }
+    }
+
+    /// Cut a streamed response off so the client sees it is incomplete
+    pub fn abort(&mut self) {
+        if let Some(frames) = self.frames.take() {
+            let _ = send_within(&frames, ResponseFrame::Abort, self.timeout);
+        }
+    }
+
+    /// The response as written, if the guest started one. Trailers are only
+    /// delivered when streaming over HTTP/2.
+    pub fn finish(self) -> Option<HttpResponse> {
+        let (status_code, headers) = self.head?;
+        Some(HttpResponse { status_code, headers, body: self.body })
+    }
+}
+
+/// Queue a frame for the client, waiting while it is behind, but no longer
+/// than `timeout`; a client that stays behind is treated as gone. Must be
+/// called from a thread of the runtime's blocking pool.
+fn send_within<T>(frames: &mpsc::Sender<T>, frame: T, timeout: Option<Duration>) -> Result<()> {
+    let sent = match timeout {
+        Some(timeout) => Handle::current().block_on(frames.send_timeout(frame, timeout)).is_ok(),
+        None => frames.blocking_send(frame).is_ok(),
+    };
+    sent.then_some(()).ok_or_else(|| anyhow!("Client disconnected"))
+}
+
+/// Check a close code a guest wants to send (RFC 6455 section 7.4); the
+/// others are reserved or only reported locally
+fn close_code(code: i32) -> Result<u16> {
+    match code {
+        1000..=1003 | 1007..=1014 | 3000..=4999 => Ok(code as u16),
+        _ => Err(anyhow!("Invalid close code: {}", code)),
+    }
+}
+
+/// The WebSocket connection a session serves, for the `ws_*` host calls
+pub struct Socket {
+    frames: mpsc::Sender<Message>, // Frames on their way to the client
+    timeout: Option<Duration>,     // Longest wait for a client that is behind
+    message: Bytes,                // Message `on_message` was called with
+}
+
+impl Socket {
+    pub fn new(frames: mpsc::Sender<Message>, timeout: Option<Duration>) -> Self {
+        Socket { frames, timeout, message: Bytes::new() }
+    }
+
+    pub fn set_message(&mut self, message: Bytes) {
+        self.message = message;
+    }
+
+    /// Waits while the client is behind, up to the timeout; must not be
+    /// called from an async task
+    fn send(&self, frame: Message) -> Result<()> {
+        send_within(&self.frames, frame, self.timeout)
+    }
+}
 
 pub struct Host;
 
 impl Host {
     /// Host function to log messages from WASM
     pub fn log(
-        mut caller: Caller<'_, WasiCtx>,
+        mut caller: Caller<'_, HostState>,
         topic_ptr: i32,
         topic_len: i32,
         msg_ptr: i32,
         msg_len: i32,
     ) -> Result<()> {
+        let _span = trace::start("host.log");
+
         // Retrieve the memory export
-        let memory = match caller.get_export("memory") {
-            Some(Extern::Memory(mem)) => mem,
-            _ => return Err(anyhow!("Failed to find memory")),
-        };
+        let memory = Self::memory(&mut caller)?;
 
         // Read the topic and message strings from the memory
         let topic = Self::read_string_from_memory(&memory, &mut caller, topic_ptr, topic_len)?;
@@ -32,14 +230,306 @@ impl Host {
         Ok(())
     }
 
+    /// Host function to log a leveled message with key/value fields from WASM.
+    /// `level` is 0 (trace) to 4 (error); the fields are a JSON object, or empty.
+    #[allow(clippy::too_many_arguments)]
+    pub fn log_event(
+        mut caller: Caller<'_, HostState>,
+        level: i32,
+        topic_ptr: i32,
+        topic_len: i32,
+        msg_ptr: i32,
+        msg_len: i32,
+        fields_ptr: i32,
+        fields_len: i32,
+    ) -> Result<()> {
+        let _span = trace::start("host.log_event");
+        let level = Level::from_i32(level).ok_or_else(|| anyhow!("Invalid log level: {}", level))?;
+
+        let memory = Self::memory(&mut caller)?;
+        let topic = Self::read_string_from_memory(&memory, &mut caller, topic_ptr, topic_len)?;
+        let message = Self::read_string_from_memory(&memory, &mut caller, msg_ptr, msg_len)?;
+        let fields = Self::read_string_from_memory(&memory, &mut caller, fields_ptr, fields_len)?;
+
+        log::log_with(&topic, level, &message, &Self::parse_fields(&fields)?);
+
+        Ok(())
+    }
+
+    /// Host function giving the guest the current W3C `traceparent`, so it can
+    /// be forwarded on outbound calls. Writes it into the buffer when it fits
+    /// and returns its length, or 0 when there is no active trace.
+    pub fn traceparent(mut caller: Caller<'_, HostState>, buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        // Read before opening our own span so the guest sees its execution span
+        let live = || match caller.data_mut().rng.as_mut() {
+            Some(rng) => trace::current().map(|context| determinism::traceparent(rng, context.sampled)),
+            None => trace::current_traceparent(),
+        };
+        let Some(traceparent) = record::host_call("traceparent", live)? else {
+            return Ok(0);
+        };
+        let _span = trace::start("host.traceparent");
+
+        Self::write_if_fits(&mut caller, buf_ptr, buf_len, traceparent.as_bytes())
+    }
+
+    /// Host function giving the guest the ID of the request being served.
+    /// Writes it into the buffer when it fits and returns its length.
+    pub fn request_id(mut caller: Caller<'_, HostState>, buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        let _span = trace::start("host.request_id");
+        let request_id = record::host_call("request_id", || log::current_context().map(|context| context.request_id))?;
+        let Some(request_id) = request_id else {
+            return Ok(0);
+        };
+
+        Self::write_if_fits(&mut caller, buf_ptr, buf_len, request_id.as_bytes())
+    }
+
+    /// Host function giving the guest the verified caller as JSON
+    /// (`{"id", "scheme", "claims"}`). Writes it into the buffer when it fits
+    /// and returns its length, or 0 when the request was not authenticated.
+    pub fn principal(mut caller: Caller<'_, HostState>, buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        let _span = trace::start("host.principal");
+        let principal = caller.data().principal.clone();
+        let Some(principal) = record::host_call("principal", || principal)? else {
+            return Ok(0);
+        };
+
+        Self::write_if_fits(&mut caller, buf_ptr, buf_len, principal.as_bytes())
+    }
+
+    /// Host function reading the next part of the request body into the
+    /// buffer. Returns the number of bytes written, or 0 at the end of the
+    /// body. For applets that stream their body this waits for it to arrive.
+    pub fn read_body(mut caller: Caller<'_, HostState>, buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        let _span = trace::start("host.read_body");
+        if buf_len <= 0 {
+            return Err(anyhow!("Buffer length must be positive"));
+        }
+
+        let mut failure = None;
+        let live = || match caller.data_mut().body.read(buf_len as usize) {
+            Ok(chunk) => chunk.map(|chunk| record::encode_body(&chunk)),
+            Err(e) => {
+                failure = Some(e);
+                None
+            }
+        };
+        let chunk = record::host_call("read_body", live)?;
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+        let Some(chunk) = chunk else {
+            return Ok(0);
+        };
+
+        let chunk = record::decode_body(&chunk);
+        if chunk.len() > buf_len as usize {
+            return Err(anyhow!("Recorded body chunk does not fit the buffer"));
+        }
+        Self::write_if_fits(&mut caller, buf_ptr, buf_len, &chunk)
+    }
+
+    /// Host function starting a response with a status code and headers,
+    /// given as a JSON object of names to a value or list of values. The
+    /// guest's return value is ignored once it has started a response.
+    pub fn response_start(
+        mut caller: Caller<'_, HostState>,
+        status: i32,
+        headers_ptr: i32,
+        headers_len: i32,
+    ) -> Result<()> {
+        let _span = trace::start("host.response_start");
+        let status = u16::try_from(status)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| anyhow!("Invalid status code: {}", status))?;
+
+        let memory = Self::memory(&mut caller)?;
+        let headers = Self::read_string_from_memory(&memory, &mut caller, headers_ptr, headers_len)?;
+        let headers = Self::parse_headers(&headers)?;
+        caller.data_mut().response.start(status.as_u16(), headers)
+    }
+
+    /// Host function appending a chunk to the response body, starting a 200
+    /// response without headers if none was started. Waits while the client
+    /// is behind.
+    pub fn response_write(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) -> Result<()> {
+        let _span = trace::start("host.response_write");
+        let memory = Self::memory(&mut caller)?;
+        let chunk = Bytes::from(Self::read_bytes_from_memory(&memory, &mut caller, ptr, len)?.to_vec());
+        caller.data_mut().response.write(chunk)
+    }
+
+    /// Host function ending the response with trailers, given like the
+    /// headers of `response_start`. Nothing can be written afterwards.
+    /// Returns 1 when the trailers reach the client and 0 when they are
+    /// dropped: only streamed responses over HTTP/2 carry them, so guests
+    /// should not rely on them for HTTP/1.1 clients.
+    pub fn response_trailers(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) -> Result<i32> {
+        let _span = trace::start("host.response_trailers");
+        let memory = Self::memory(&mut caller)?;
+        let trailers = Self::read_string_from_memory(&memory, &mut caller, ptr, len)?;
+        let trailers = Self::parse_headers(&trailers)?;
+        let sent = caller.data_mut().response.end(trailers)?;
+        let sent = record::host_call("response_trailers", || Some(sent.to_string()))?;
+        Ok(i32::from(sent.as_deref() == Some("true")))
+    }
+
+    /// Host function giving the guest the WebSocket message it is handling.
+    /// Writes it into the buffer when it fits and returns its length.
+    pub fn ws_message(mut caller: Caller<'_, HostState>, buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        let _span = trace::start("host.ws_message");
+        let message = Self::socket(&mut caller)?.message.clone();
+
+        Self::write_if_fits(&mut caller, buf_ptr, buf_len, &message)
+    }
+
+    /// Host function sending a WebSocket frame to the client: text when
+    /// `is_text` is non-zero, binary otherwise. Waits while the client is behind.
+    pub fn ws_send(mut caller: Caller<'_, HostState>, ptr: i32, len: i32, is_text: i32) -> Result<()> {
+        let _span = trace::start("host.ws_send");
+        let memory = Self::memory(&mut caller)?;
+        let frame = match is_text {
+            0 => Message::binary(Self::read_bytes_from_memory(&memory, &mut caller, ptr, len)?.to_vec()),
+            _ => Message::text(Self::read_string_from_memory(&memory, &mut caller, ptr, len)?),
+        };
+        Self::socket(&mut caller)?.send(frame)
+    }
+
+    /// Host function closing the WebSocket connection with a close code;
+    /// `on_close` follows once the client acknowledges it. Only codes an
+    /// endpoint may send are accepted: 1000-1003, 1007-1014 and 3000-4999.
+    pub fn ws_close(mut caller: Caller<'_, HostState>, code: i32) -> Result<()> {
+        let _span = trace::start("host.ws_close");
+        let code = close_code(code)?;
+        Self::socket(&mut caller)?.send(Message::close_with(code, ""))
+    }
+
+    /// Host function publishing an event to one of the applet's channels,
+    /// which clients subscribe to at `/_events/<applet>/<channel>`. The
+    /// event type may be empty. Returns the event's ID.
+    #[allow(clippy::too_many_arguments)]
+    pub fn publish(
+        mut caller: Caller<'_, HostState>,
+        channel_ptr: i32,
+        channel_len: i32,
+        event_ptr: i32,
+        event_len: i32,
+        data_ptr: i32,
+        data_len: i32,
+    ) -> Result<i64> {
+        let _span = trace::start("host.publish");
+        let memory = Self::memory(&mut caller)?;
+        let channel = Self::read_string_from_memory(&memory, &mut caller, channel_ptr, channel_len)?;
+        let event = Self::read_string_from_memory(&memory, &mut caller, event_ptr, event_len)?;
+        let data = Self::read_string_from_memory(&memory, &mut caller, data_ptr, data_len)?;
+        if !events::valid_channel(&channel) {
+            return Err(anyhow!("Invalid channel name: '{}'", channel));
+        }
+        if event.contains(['\r', '\n']) {
+            return Err(anyhow!("Event type must not contain line breaks"));
+        }
+        if data.contains('\r') {
+            return Err(anyhow!("Event data must not contain carriage returns"));
+        }
+
+        let applet = log::current_context().map(|context| context.applet).ok_or_else(|| anyhow!("No applet is running"))?;
+        let event = (!event.is_empty()).then_some(event);
+        let id = record::host_call("publish", || Some(events::publish(applet, &channel, event, data).to_string()))?;
+        id.and_then(|id| id.parse().ok()).ok_or_else(|| anyhow!("Recorded event ID is invalid"))
+    }
+
+    /// Helper to find the connection a WebSocket host call acts on
+    fn socket<'a>(caller: &'a mut Caller<'_, HostState>) -> Result<&'a mut Socket> {
+        caller.data_mut().socket.as_mut().ok_or_else(|| anyhow!("Not serving a WebSocket connection"))
+    }
+
+    /// Helper to copy `bytes` into guest memory when the buffer is large
+    /// enough; returns the full length so the guest can retry with more room
+    fn write_if_fits(caller: &mut Caller<'_, HostState>, buf_ptr: i32, buf_len: i32, bytes: &[u8]) -> Result<i32> {
+        if bytes.len() <= buf_len as usize {
+            let memory = Self::memory(caller)?;
+            memory
+                .write(caller, buf_ptr as usize, bytes)
+                .map_err(|_| anyhow!("Pointer and length out of bounds"))?;
+        }
+        Ok(bytes.len() as i32)
+    }
+
+    /// Helper to turn a JSON object into ordered key/value pairs
+    fn parse_fields(fields: &str) -> Result<Vec<(String, String)>> {
+        if fields.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        match serde_json::from_str::<Value>(fields)? {
+            Value::Object(map) => Ok(map
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => (key, s),
+                    other => (key, other.to_string()),
+                })
+                .collect()),
+            _ => Err(anyhow!("Log fields must be a JSON object")),
+        }
+    }
+
+    /// Helper to turn a JSON object of header names to a value or list of
+    /// values into a header map
+    fn parse_headers(headers: &str) -> Result<HeaderMap> {
+        let mut map = HeaderMap::new();
+        if headers.trim().is_empty() {
+            return Ok(map);
+        }
+
+        let Value::Object(object) = serde_json::from_str::<Value>(headers)? else {
+            return Err(anyhow!("Headers must be a JSON object"));
+        };
+        for (name, value) in object {
+            let values = match value {
+                Value::Array(values) => values,
+                value => vec![value],
+            };
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+            for value in values {
+                let Value::String(value) = value else {
+                    return Err(anyhow!("Value of header '{}' must be a string", name));
+                };
+                map.append(&name, HeaderValue::from_str(&value)?);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Helper to find the guest's exported memory
+    fn memory(caller: &mut Caller<'_, HostState>) -> Result<Memory> {
+        match caller.get_export("memory") {
+            Some(Extern::Memory(mem)) => Ok(mem),
+            _ => Err(anyhow!("Failed to find memory")),
+        }
+    }
+
     /// Helper to read a string from WASM memory
     fn read_string_from_memory(
         memory: &Memory,
-        caller: &mut Caller<'_, WasiCtx>,
+        caller: &mut Caller<'_, HostState>,
         ptr: i32,
         len: i32,
     ) -> Result<String> {
-        let data = memory.data(&caller);
+        let bytes = Self::read_bytes_from_memory(memory, caller, ptr, len)?;
+        let s = std::str::from_utf8(bytes)?.to_string();
+        Ok(s)
+    }
+
+    /// Helper to borrow a byte range of WASM memory
+    fn read_bytes_from_memory<'a>(
+        memory: &Memory,
+        caller: &'a mut Caller<'_, HostState>,
+        ptr: i32,
+        len: i32,
+    ) -> Result<&'a [u8]> {
+        let data = memory.data(caller);
         let start = ptr as usize;
         let end = start.checked_add(len as usize)
             .ok_or_else(|| anyhow!("Integer overflow when calculating string bounds"))?;
@@ -48,8 +538,129 @@ impl Host {
             return Err(anyhow!("Pointer and length out of bounds"));
         }
 
-        let bytes = &data[start..end];
-        let s = std::str::from_utf8(bytes)?.to_string();
-        Ok(s)
+        Ok(&data[start..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::StreamedHead;
+
+    #[test]
+    fn body_reader_reads_the_buffered_part_then_the_stream() {
+        let (chunks, receiver) = mpsc::channel(4);
+        chunks.try_send(Ok(Bytes::from("cde"))).unwrap();
+        drop(chunks);
+        let mut reader = BodyReader::new(Bytes::from("ab"), Some(BodyStream::new(receiver, None)));
+        assert_eq!(reader.read(10).unwrap().unwrap(), "ab");
+        assert_eq!(reader.read(2).unwrap().unwrap(), "cd");
+        assert_eq!(reader.read(2).unwrap().unwrap(), "e");
+        assert!(reader.read(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn body_reader_passes_on_stream_errors() {
+        let (chunks, receiver) = mpsc::channel(4);
+        chunks.try_send(Err(BodyError::TooLarge(5))).unwrap();
+        let mut reader = BodyReader::new(Bytes::new(), Some(BodyStream::new(receiver, None)));
+        assert!(matches!(reader.read(10), Err(BodyError::TooLarge(5))));
+    }
+
+    #[test]
+    fn body_reader_gives_up_on_a_silent_client() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (_chunks, receiver) = mpsc::channel::<Result<Bytes, BodyError>>(1);
+        let stream = BodyStream::new(receiver, Some(Duration::from_millis(50)));
+        let mut reader = BodyReader::new(Bytes::new(), Some(stream));
+        let read = runtime.block_on(runtime.spawn_blocking(move || reader.read(10))).unwrap();
+        assert!(matches!(read, Err(BodyError::Failed(_))));
+    }
+
+    /// Run `write` against a writer streaming into a sink, as the guest would
+    /// from the blocking pool, and return what it produced with the frames
+    /// the client was sent
+    fn streamed<T: Send + 'static>(
+        capacity: usize,
+        trailers: bool,
+        timeout: Option<Duration>,
+        write: impl FnOnce(&mut ResponseWriter) -> T + Send + 'static,
+    ) -> (T, Option<StreamedHead>) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (sink, started) = ResponseSink::new(capacity, trailers);
+        let mut writer = ResponseWriter::new(Some(sink), None, timeout);
+        let written = runtime.block_on(runtime.spawn_blocking(move || write(&mut writer))).unwrap();
+        (written, runtime.block_on(started).ok())
+    }
+
+    fn data(frame: Option<ResponseFrame>) -> Option<Bytes> {
+        match frame {
+            Some(ResponseFrame::Data(chunk)) => Some(chunk),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn response_writer_streams_the_head_body_and_trailers() {
+        let (ended, head) = streamed(4, true, None, |writer| {
+            writer.start(201, HeaderMap::new())?;
+            writer.write(Bytes::from("ab"))?;
+            writer.write(Bytes::from("cd"))?;
+            writer.end(HeaderMap::from_iter([(HeaderName::from_static("grpc-status"), HeaderValue::from(0))]))
+        });
+        assert!(ended.unwrap());
+        let mut head = head.unwrap();
+        assert_eq!(head.status_code, 201);
+        assert_eq!(data(head.frames.try_recv().ok()).unwrap(), "ab");
+        assert_eq!(data(head.frames.try_recv().ok()).unwrap(), "cd");
+        let Ok(ResponseFrame::Trailers(trailers)) = head.frames.try_recv() else {
+            panic!("expected trailers");
+        };
+        assert_eq!(trailers["grpc-status"], "0");
+        assert!(head.frames.try_recv().is_err());
+    }
+
+    #[test]
+    fn response_writer_skips_trailers_the_connection_cannot_carry() {
+        let (ended, head) = streamed(4, false, None, |writer| {
+            writer.write(Bytes::from("ab"))?;
+            writer.end(HeaderMap::new())
+        });
+        assert!(!ended.unwrap());
+        let mut head = head.unwrap();
+        assert_eq!(head.status_code, 200);
+        assert_eq!(data(head.frames.try_recv().ok()).unwrap(), "ab");
+        assert!(head.frames.try_recv().is_err());
+    }
+
+    #[test]
+    fn response_writer_gives_up_on_a_client_that_stays_behind() {
+        let (written, head) = streamed(1, false, Some(Duration::from_millis(50)), |writer| {
+            writer.write(Bytes::from("a"))?;
+            writer.write(Bytes::from("b"))
+        });
+        assert_eq!(written.unwrap_err().to_string(), "Client disconnected");
+        assert_eq!(data(head.unwrap().frames.try_recv().ok()).unwrap(), "a");
+    }
+
+    #[test]
+    fn guests_send_only_valid_close_codes() {
+        for code in [1000, 1003, 1007, 1014, 3000, 4999] {
+            assert_eq!(close_code(code).unwrap(), code as u16);
+        }
+        for code in [-1, 0, 999, 1004, 1005, 1006, 1015, 2999, 5000, 70000] {
+            assert!(close_code(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn response_writer_collects_the_response_without_a_sink() {
+        let mut writer = ResponseWriter::new(None, Some(7), None);
+        writer.write(Bytes::from("ab")).unwrap();
+        assert!(!writer.end(HeaderMap::new()).unwrap());
+        assert!(writer.write(Bytes::from("cd")).is_err());
+        let response = writer.finish().unwrap();
+        assert_eq!((response.status_code, response.body.as_slice()), (200, b"ab".as_slice()));
+        assert_eq!(response.headers[determinism::SEED_HEADER], "7");
     }
 }
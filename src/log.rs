@@ -1,10 +1,297 @@
-use crate::config;
+use crate::config::{self, Config};
+use crate::log_sink::{self, Record};
+use anyhow::{anyhow, Result};
 use chrono::Local; // For timestamps
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use uuid::Uuid;
 
+/// Severity of a log line, ordered from most to least verbose
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Map the numeric level used by the guest ABI (0 = trace .. 4 = error)
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Level::Trace),
+            1 => Some(Level::Debug),
+            2 => Some(Level::Info),
+            3 => Some(Level::Warn),
+            4 => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    /// Parse a level name such as "debug" or "WARN"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "trace" => Some(Level::Trace),
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" | "warning" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which topics are logged, and from which level. Topic patterns are
+/// either exact names, `prefix*` wildcards (e.g. `applet:*`) or `*`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TopicFilter {
+    pub topics: BTreeSet<String>,        // Enabled topic patterns
+    pub level: Level,                    // Minimum level when no pattern below matches
+    pub levels: BTreeMap<String, Level>, // Minimum level per topic pattern
+}
+
+impl TopicFilter {
+    /// Seed a filter from the startup configuration
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            topics: config.log_topics.iter().cloned().collect(),
+            level: config.log_level,
+            levels: config.log_levels.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+        }
+    }
+
+    /// Whether a line at `level` under `topic` should be logged
+    pub fn enabled(&self, topic: &str, level: Level) -> bool {
+        self.topics.iter().any(|pattern| topic_matches(pattern, topic))
+            && level >= self.min_level(topic)
+    }
+
+    /// Minimum level for `topic`: an exact entry wins, then the longest
+    /// matching wildcard, then the default level
+    pub fn min_level(&self, topic: &str) -> Level {
+        if let Some(level) = self.levels.get(topic) {
+            return *level;
+        }
+        self.levels
+            .iter()
+            .filter(|(pattern, _)| topic_matches(pattern, topic))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    /// Apply lines of a topics file: `topic` or `topic=level`, `#` comments
+    fn apply_file(&mut self, contents: &str) -> Result<()> {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((topic, level)) => {
+                    let level = Level::parse(level.trim())
+                        .ok_or_else(|| anyhow!("Invalid log level in '{}'", line))?;
+                    self.topics.insert(topic.trim().to_string());
+                    self.levels.insert(topic.trim().to_string(), level);
+                }
+                None => {
+                    self.topics.insert(line.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Match a topic against an exact name or a trailing-`*` wildcard
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
+
+static FILTER: OnceLock<RwLock<TopicFilter>> = OnceLock::new();
+
+fn filter() -> &'static RwLock<TopicFilter> {
+    FILTER.get_or_init(|| RwLock::new(TopicFilter::from_config(config::global_config())))
+}
+
+/// The topic filter currently in effect
+pub fn topic_filter() -> TopicFilter {
+    filter().read().unwrap().clone()
+}
+
+/// Replace the topic filter without restarting
+pub fn set_topic_filter(new_filter: TopicFilter) {
+    *filter().write().unwrap() = new_filter;
+}
+
+/// Rebuild the topic filter from the startup configuration plus the
+/// `--log-topics-file`, if one is configured
+pub fn reload_topics(config: &Config) -> Result<()> {
+    let mut new_filter = TopicFilter::from_config(config);
+    if let Some(path) = &config.log_topics_file {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read '{}': {}", path.display(), e))?;
+        new_filter.apply_file(&contents)?;
+    }
+    set_topic_filter(new_filter);
+    Ok(())
+}
+
+/// Invocation details attached to every line logged while an applet runs
+#[derive(Clone, Debug)]
+pub struct LogContext {
+    pub applet: Uuid,         // Applet being executed
+    pub request_id: String,   // Identifier of the request being served
+    pub started_at: Instant,  // When the invocation started
+}
+
+/// Records buffered for live tail subscribers before slow ones start lagging
+const TAIL_CAPACITY: usize = 1024;
+
+static TAIL: OnceLock<broadcast::Sender<Record>> = OnceLock::new();
+
+fn tail() -> &'static broadcast::Sender<Record> {
+    TAIL.get_or_init(|| broadcast::channel(TAIL_CAPACITY).0)
+}
+
+/// Subscribe to every record logged from now on
+pub fn subscribe() -> broadcast::Receiver<Record> {
+    tail().subscribe()
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<LogContext>> = const { RefCell::new(None) };
+}
+
+/// Guard returned by `enter`; clears the invocation context when dropped
+pub struct ContextGuard {
+    previous: Option<LogContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|ctx| *ctx.borrow_mut() = previous);
+    }
+}
+
+/// The invocation context attached to this thread, if any
+pub fn current_context() -> Option<LogContext> {
+    CONTEXT.with(|ctx| ctx.borrow().clone())
+}
+
+/// Attach an invocation context to all log lines emitted on this thread
+pub fn enter(context: LogContext) -> ContextGuard {
+    let previous = CONTEXT.with(|ctx| ctx.borrow_mut().replace(context));
+    ContextGuard { previous }
+}
+
+/// Log an informational message under the given topic
 pub fn log(topic: &str, message: &str) {
-    let config = config::global_config(); // Access the global configuration
-    if config.log_topics.contains(topic) {
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        println!("[{}] [{}]: {}", timestamp, topic, message);
+    log_with(topic, Level::Info, message, &[]);
+}
+
+/// Log a message with an explicit level and key/value fields
+pub fn log_with(topic: &str, level: Level, message: &str, fields: &[(String, String)]) {
+    if !filter().read().unwrap().enabled(topic, level) {
+        return;
+    }
+
+    let context = current_context();
+    let record = Record {
+        timestamp: Local::now(),
+        level,
+        topic: topic.to_string(),
+        message: message.to_string(),
+        fields: fields.to_vec(),
+        elapsed: context.as_ref().map(|ctx| ctx.started_at.elapsed()),
+        context,
+    };
+    log_sink::dispatch(&record);
+
+    // Feed live tail subscribers, if any are listening
+    let tail = tail();
+    if tail.receiver_count() > 0 {
+        let _ = tail.send(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_of(topics: &[&str], levels: &[(&str, Level)]) -> TopicFilter {
+        TopicFilter {
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            level: Level::Info,
+            levels: levels.iter().map(|(pattern, level)| (pattern.to_string(), *level)).collect(),
+        }
+    }
+
+    #[test]
+    fn wildcards_match_prefixes() {
+        let filter = filter_of(&["applet:*", "access"], &[]);
+        assert!(filter.enabled("applet:echo", Level::Info));
+        assert!(filter.enabled("applet:", Level::Info));
+        assert!(filter.enabled("access", Level::Info));
+        assert!(!filter.enabled("accesses", Level::Info));
+        assert!(!filter.enabled("substrate", Level::Info));
+        assert!(!filter.enabled("applet:echo", Level::Debug));
+
+        assert!(filter_of(&["*"], &[]).enabled("anything", Level::Info));
+    }
+
+    #[test]
+    fn exact_levels_beat_longest_wildcard() {
+        let filter = filter_of(
+            &["*"],
+            &[
+                ("*", Level::Error),
+                ("applet:*", Level::Warn),
+                ("applet:echo*", Level::Debug),
+                ("applet:echo", Level::Trace),
+            ],
+        );
+        assert_eq!(filter.min_level("applet:echo"), Level::Trace);
+        assert_eq!(filter.min_level("applet:echo2"), Level::Debug);
+        assert_eq!(filter.min_level("applet:other"), Level::Warn);
+        assert_eq!(filter.min_level("substrate"), Level::Error);
+        assert_eq!(TopicFilter::default().min_level("substrate"), Level::Info);
+    }
+
+    #[test]
+    fn topics_file_adds_topics_and_levels() {
+        let mut filter = filter_of(&["substrate"], &[]);
+        filter.apply_file("# comment\naccess\n\napplet:* = debug # trailing\n").unwrap();
+        assert!(filter.enabled("access", Level::Info));
+        assert!(filter.enabled("applet:echo", Level::Debug));
+        assert!(filter.apply_file("applet:*=loud").is_err());
     }
 }
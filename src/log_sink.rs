@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde_json::{Map, Value};
+
+use crate::config::Config;
+use crate::log::{Level, LogContext};
+
+/// A single log line, handed to every configured sink
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub timestamp: DateTime<Local>,
+    pub level: Level,
+    pub topic: String,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+    pub context: Option<LogContext>,
+    pub elapsed: Option<Duration>, // Time since the invocation started
+}
+
+impl Record {
+    /// Render as `[timestamp] [LEVEL] [topic] [context]: message key=value`
+    pub fn to_text(&self) -> String {
+        let mut line = format!(
+            "[{}] [{}] [{}]",
+            self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            self.level,
+            self.topic
+        );
+
+        if let Some(ctx) = &self.context {
+            line.push_str(&format!(
+                " [applet={} request={} +{:.3}ms]",
+                ctx.applet,
+                ctx.request_id,
+                self.elapsed.unwrap_or_default().as_secs_f64() * 1000.0
+            ));
+        }
+
+        line.push_str(": ");
+        line.push_str(&self.message);
+        for (key, value) in &self.fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+
+    /// Render as a single JSON object
+    pub fn to_json(&self) -> String {
+        let mut object = Map::new();
+        object.insert("timestamp".into(), Value::String(self.timestamp.to_rfc3339()));
+        object.insert("level".into(), Value::String(self.level.as_str().to_string()));
+        object.insert("topic".into(), Value::String(self.topic.clone()));
+        object.insert("message".into(), Value::String(self.message.clone()));
+
+        if let Some(ctx) = &self.context {
+            object.insert("applet".into(), Value::String(ctx.applet.to_string()));
+            object.insert("request_id".into(), Value::String(ctx.request_id.clone()));
+            object.insert(
+                "elapsed_ms".into(),
+                serde_json::json!(self.elapsed.unwrap_or_default().as_secs_f64() * 1000.0),
+            );
+        }
+
+        if !self.fields.is_empty() {
+            let fields = self
+                .fields
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                .collect();
+            object.insert("fields".into(), Value::Object(fields));
+        }
+
+        Value::Object(object).to_string()
+    }
+}
+
+/// Which sink to write to, as selected with `--log-sink`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkKind {
+    Text,          // Human-readable lines on stdout
+    Json,          // JSON lines on stdout
+    File(PathBuf), // Rotating file of JSON lines
+    Memory,        // In-memory ring buffer of recent lines
+}
+
+impl SinkKind {
+    /// Parse `text`, `json`, `memory` or `file:<path>`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(SinkKind::Text),
+            "json" => Some(SinkKind::Json),
+            "memory" => Some(SinkKind::Memory),
+            _ => value
+                .strip_prefix("file:")
+                .filter(|path| !path.is_empty())
+                .map(|path| SinkKind::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl std::fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SinkKind::Text => f.write_str("text"),
+            SinkKind::Json => f.write_str("json"),
+            SinkKind::Memory => f.write_str("memory"),
+            SinkKind::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Destination for log records
+pub trait Sink: Send + Sync {
+    fn write(&self, record: &Record);
+
+    /// Make sure everything written so far has reached its destination
+    fn flush(&self) {}
+}
+
+/// Writes text lines to stdout
+pub struct StdoutText;
+
+impl Sink for StdoutText {
+    fn write(&self, record: &Record) {
+        println!("{}", record.to_text());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Writes JSON lines to stdout
+pub struct StdoutJson;
+
+impl Sink for StdoutJson {
+    fn write(&self, record: &Record) {
+        println!("{}", record.to_json());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Appends JSON lines to a file, rotating it once it grows past
+/// `max_bytes` or has been open for longer than `max_age`
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    state: Mutex<Option<OpenFile>>,
+}
+
+struct OpenFile {
+    file: File,
+    written: u64,
+    opened_at: Instant,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, max_bytes: u64, max_age: Duration) -> Self {
+        Self {
+            path,
+            max_bytes,
+            max_age,
+            state: Mutex::new(None),
+        }
+    }
+
+    fn open(&self) -> std::io::Result<OpenFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let written = file.metadata()?.len();
+        Ok(OpenFile {
+            file,
+            written,
+            opened_at: Instant::now(),
+        })
+    }
+
+    /// Move the current file aside as `<path>.<timestamp>`
+    fn rotate(&self) -> std::io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", Local::now().format("%Y%m%d%H%M%S%3f")));
+        fs::rename(&self.path, rotated)
+    }
+
+    fn needs_rotation(&self, open: &OpenFile) -> bool {
+        (self.max_bytes > 0 && open.written >= self.max_bytes)
+            || (!self.max_age.is_zero() && open.opened_at.elapsed() >= self.max_age)
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&self, record: &Record) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.as_ref().is_some_and(|open| self.needs_rotation(open)) {
+            *state = None;
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+
+        if state.is_none() {
+            match self.open() {
+                Ok(open) => *state = Some(open),
+                Err(e) => {
+                    eprintln!("Failed to open log file {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+
+        let open = state.as_mut().expect("log file is open");
+        let line = format!("{}\n", record.to_json());
+        match open.file.write_all(line.as_bytes()) {
+            Ok(()) => open.written += line.len() as u64,
+            Err(e) => eprintln!("Failed to write log file {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(open) = self.state.lock().unwrap().as_mut() {
+            if let Err(e) = open.file.sync_data() {
+                eprintln!("Failed to flush log file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Keeps the most recent `capacity` records in memory
+pub struct RingBuffer {
+    capacity: usize,
+    records: Mutex<VecDeque<Record>>,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Return up to `count` of the most recent records accepted by
+    /// `filter`, oldest first
+    pub fn recent(&self, count: usize, filter: impl Fn(&Record) -> bool) -> Vec<Record> {
+        let records = self.records.lock().unwrap();
+        let mut matching: Vec<Record> = records
+            .iter()
+            .rev()
+            .filter(|record| filter(record))
+            .take(count)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+impl Sink for RingBuffer {
+    fn write(&self, record: &Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record.clone());
+    }
+}
+
+impl Sink for &'static RingBuffer {
+    fn write(&self, record: &Record) {
+        (**self).write(record);
+    }
+}
+
+struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+    memory: Option<&'static RingBuffer>,
+}
+
+static SINKS: OnceLock<Sinks> = OnceLock::new();
+
+/// Build the sinks selected in the configuration. Must be called once,
+/// after the configuration has been initialized.
+pub fn init(config: &Config) {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    let mut memory = None;
+
+    for kind in &config.log_sinks {
+        match kind {
+            SinkKind::Text => sinks.push(Box::new(StdoutText)),
+            SinkKind::Json => sinks.push(Box::new(StdoutJson)),
+            SinkKind::File(path) => sinks.push(Box::new(FileSink::new(
+                path.clone(),
+                config.log_file_max_bytes,
+                Duration::from_secs(config.log_file_max_age),
+            ))),
+            SinkKind::Memory => {
+                // Leaked so the buffer can be read back while also being a sink
+                let buffer: &'static RingBuffer =
+                    Box::leak(Box::new(RingBuffer::new(config.log_buffer_lines)));
+                memory = Some(buffer);
+                sinks.push(Box::new(buffer));
+            }
+        }
+    }
+
+    if SINKS.set(Sinks { sinks, memory }).is_err() {
+        panic!("Log sinks have already been initialized!");
+    }
+}
+
+/// Hand a record to every configured sink; falls back to stdout text
+/// when the sinks have not been initialized yet
+pub fn dispatch(record: &Record) {
+    match SINKS.get() {
+        Some(sinks) => sinks.sinks.iter().for_each(|sink| sink.write(record)),
+        None => StdoutText.write(record),
+    }
+}
+
+/// Flush every configured sink
+pub fn flush() {
+    match SINKS.get() {
+        Some(sinks) => sinks.sinks.iter().for_each(|sink| sink.flush()),
+        None => StdoutText.flush(),
+    }
+}
+
+/// The in-memory ring buffer, when the `memory` sink is enabled
+pub fn memory_buffer() -> Option<&'static RingBuffer> {
+    SINKS.get().and_then(|sinks| sinks.memory)
+}
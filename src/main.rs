@@ -1,17 +1,35 @@
+mod auth; // Admin tokens and applet authentication
+mod bench; // Benchmarking subcommand
 mod cli; // CLI module
+mod commands; // One-shot subcommands (run, inspect, validate)
 mod config; // Config module
+mod config_file; // TOML configuration file
+mod cors; // Cross-origin resource sharing
 mod applet_store; // Applet store module
 mod net; // Networking module
 mod types;
 mod log;
+mod log_sink;
+mod determinism; // Seeded execution
+mod events; // Server-sent event channels
 mod executor;
 mod host;
 mod runner;
+mod admin; // Admin routes
+mod metrics; // Prometheus metrics
+mod manifest; // Deployment manifests
+mod record; // Request recording and replay
+mod throttle; // Rate limits and concurrency caps
+mod tls; // TLS termination
+mod trace; // Distributed tracing
+mod watch; // Hot reload of a directory of applets
 
-use cli::parse_args;
-use config::init_config;
+use cli::{parse_args, Command, ConfigAction};
+use config::{init_config, Config};
 use applet_store::AppletStore;
+use runner::Runner;
 use std::sync::Arc;
+use std::path::Path;
 use std::process;
 
 #[tokio::main]
@@ -19,43 +37,221 @@ async fn main() {
     // Parse CLI arguments
     let args = parse_args();
 
-    // Initialize global configuration with CLI arguments
-    init_config(args.clone());
+    // `substrate config check` validates and prints the configuration only
+    if let Some(Command::Config { action: ConfigAction::Check }) = &args.command {
+        match config::check(args.clone()) {
+            Ok(effective) => {
+                print!("{}", effective);
+                println!("# Configuration OK");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Configuration error: {:#}", e);
+                process::exit(1);
+            }
+        }
+    }
 
-    // Access the global configuration (optional logging/debugging)
+    // Initialize global configuration from the config file, environment and CLI
+    match Config::resolve(args.clone()) {
+        Ok(config) => init_config(config),
+        Err(e) => {
+            eprintln!("Configuration error: {:#}", e);
+            process::exit(1);
+        }
+    }
     let config = config::global_config();
+    log_sink::init(config);
+
+    // One-shot subcommands run and exit without starting the server
+    let result = match &args.command {
+        Some(Command::Run(run)) => Some(commands::run(run)),
+        Some(Command::Inspect { file }) => Some(commands::inspect(Path::new(file))),
+        Some(Command::Validate { file }) => Some(commands::validate(Path::new(file))),
+        Some(Command::Bench(bench)) => Some(bench::run(bench)),
+        Some(Command::Replay { file, recording }) => Some(commands::replay(Path::new(file), Path::new(recording))),
+        Some(Command::Serve) | Some(Command::Config { .. }) | None => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("Error: {:#}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
     log::log("substrate", "Substrate starting up");
+    trace::init(config);
+    if let Some(path) = &config.config_file {
+        log::log("substrate", &format!("Configuration loaded from {}", path.display()));
+    }
+
+    // Pick up the topics file and keep it in sync on SIGHUP
+    reload_log_topics();
+    spawn_reload_on_sighup();
+
+    // Certificates are reloaded when their files change or on SIGHUP
+    if let Some(files) = &config.tls {
+        if let Err(e) = tls::init(files) {
+            shutdown(1, &format!("{:#}", e));
+        }
+    }
+
+    // Recordings are appended as requests arrive; /readyz only checks the directory
+    if let Some(dir) = &config.record_dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            shutdown(1, &format!("Failed to create the recording directory '{}': {}", dir.display(), e));
+        }
+    }
 
     // Set up applet store
     let store = Arc::new(AppletStore::new());
 
-    // Check if a WASM file is provided
-    if let Some(filename) = args.load.as_deref() {
-        log::log("substrate", &format!("Loading WASM file: {}", filename));
-        let wasm_binary = match std::fs::read(filename) {
-            Ok(data) => data,
+    // Collect the applets given with --load and in the manifest
+    let mut specs: Vec<manifest::AppletSpec> = args.load.iter().map(|value| manifest::parse_load(value)).collect();
+    if let Some(path) = &config.manifest {
+        log::log("substrate", &format!("Loading manifest: {}", path.display()));
+        match manifest::load(path) {
+            Ok(applets) => specs.extend(applets),
             Err(e) => {
-                log::log(
+                shutdown(1, &format!("{:#}", e));
+            }
+        }
+    }
+
+    for spec in specs {
+        if let Err(e) = load_applet(&store, spec) {
+            shutdown(1, &format!("{:#}", e));
+        }
+    }
+
+    let runner = Arc::new(Runner::new(store.clone()).expect("Failed to create runner"));
+
+    // Load the watched directory and follow its changes
+    if let Some(dir) = &config.watch_dir {
+        if let Err(e) = watch::start(dir, runner.clone()) {
+            shutdown(1, &format!("{:#}", e));
+        }
+    }
+
+    if store.usage().0 == 0 && !config.admin_enabled && config.watch_dir.is_none() {
+        log::log("substrate", "No WASM file specified and the admin API is disabled. Shutting down.");
+        shutdown(1, "No WASM file specified");
+    }
+
+    // Compile the pinned applets in the background; /readyz waits for them
+    let pinned: Vec<_> = store
+        .list()
+        .into_iter()
+        .filter(|(_, metadata)| metadata.settings.pinned)
+        .collect();
+    let warm_runner = runner.clone();
+    tokio::task::spawn_blocking(move || {
+        for (uuid, metadata) in pinned {
+            if let Err(e) = warm_runner.warm(uuid) {
+                log::log_with(
                     "substrate",
-                    &format!("Failed to read the WASM file '{}': {}", filename, e),
+                    log::Level::Error,
+                    &format!("Failed to compile applet '{}': {:#}", metadata.name, e),
+                    &[],
                 );
-                shutdown(1, "Failed to read the WASM file");
-                return; // Ensure the program doesn't continue
             }
-        };
-        let uuid = store.create(wasm_binary, "Loaded Applet".to_string());
-        log::log("substrate", &format!("Applet stored with UUID: {}", uuid));
-    } else {
-        log::log("substrate", "No WASM file specified. Shutting down.");
-        shutdown(1, "No WASM file specified");
+        }
+    });
+
+    // Start the server using net.rs and serve until asked to stop
+    if let Err(e) = net::start_server(store, runner, shutdown_signal()).await {
+        shutdown(1, &format!("{:#}", e));
+    }
+
+    trace::shutdown().await;
+    shutdown(0, "Server stopped");
+}
+
+/// Resolves on SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => log::log("substrate", "Received SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => log::log("substrate", "Received SIGINT"),
+                }
+                return;
+            }
+            Err(e) => log::log("substrate", &format!("Failed to install SIGTERM handler: {}", e)),
+        }
+    }
+
+    if tokio::signal::ctrl_c().await.is_ok() {
+        log::log("substrate", "Received SIGINT");
+    }
+}
+
+/// Read an applet from disk and put it in the store
+fn load_applet(store: &AppletStore, spec: manifest::AppletSpec) -> anyhow::Result<()> {
+    let config = config::global_config();
+    log::log("substrate", &format!("Loading WASM file: {}", spec.path.display()));
+
+    let wasm_binary = std::fs::read(&spec.path)
+        .map_err(|e| anyhow::anyhow!("Failed to read the WASM file '{}': {}", spec.path.display(), e))?;
+    if config.max_applet_bytes > 0 && wasm_binary.len() > config.max_applet_bytes {
+        return Err(anyhow::anyhow!(
+            "WASM file '{}' is {} bytes, above the {} byte limit",
+            spec.path.display(),
+            wasm_binary.len(),
+            config.max_applet_bytes
+        ));
+    }
+
+    let uuid = store.create_with(wasm_binary, spec.name.clone(), spec.settings)?;
+    log::log("substrate", &format!("Applet '{}' stored with UUID: {}", spec.name, uuid));
+    Ok(())
+}
+
+/// Rebuild the logging topics from the configuration and the topics file.
+/// Without a topics file there is nothing to re-read, so topics changed
+/// through `PUT /_admin/log-topics` are kept.
+fn reload_log_topics() {
+    if config::global_config().log_topics_file.is_none() {
+        return;
+    }
+    if let Err(e) = log::reload_topics(config::global_config()) {
+        log::log_with("substrate", log::Level::Error, &format!("Failed to reload log topics: {}", e), &[]);
     }
+}
 
-    // Start the server using net.rs
-    net::start_server(store).await;
+/// Reload the logging topics and TLS certificates whenever the process receives SIGHUP
+#[cfg(unix)]
+fn spawn_reload_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::log("substrate", &format!("Failed to install SIGHUP handler: {}", e));
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::log("substrate", "Received SIGHUP, reloading log topics and TLS certificates");
+            reload_log_topics();
+            tls::reload();
+        }
+    });
 }
 
-/// Helper function to handle shutdown with logging and exit code
-fn shutdown(exit_code: i32, reason: &str) {
+#[cfg(not(unix))]
+fn spawn_reload_on_sighup() {}
+
+/// Log why the process is ending, flush the log sinks and exit
+fn shutdown(exit_code: i32, reason: &str) -> ! {
     log::log("substrate", &format!("Shutting down: {}", reason));
+    log_sink::flush();
     process::exit(exit_code);
 }
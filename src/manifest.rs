@@ -0,0 +1,118 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::applet_store::{AppletSettings, WasiSettings};
+use crate::auth::AuthPolicy;
+use crate::config::RateLimitKey;
+use crate::config_file::{CorsSection, LimitsSection};
+use crate::{cors, host};
+
+/// A deployment manifest: the applets to load at startup
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default, rename = "applet")]
+    applets: Vec<ManifestApplet>,
+}
+
+/// One `[[applet]]` entry of the manifest
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestApplet {
+    name: String,
+    path: PathBuf, // Relative paths are resolved against the manifest's directory
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    limits: LimitsSection,
+    capabilities: Option<Vec<String>>,
+    #[serde(default)]
+    wasi: WasiSettings,
+    #[serde(default)]
+    deterministic: bool,
+    #[serde(default)]
+    allow_seed_header: bool,
+    auth: Option<AuthPolicy>,
+    #[serde(default)]
+    cors: CorsSection,
+    #[serde(default)]
+    stream_body: bool,
+    #[serde(default)]
+    websocket: bool,
+}
+
+/// An applet ready to be read from disk and stored; applets deployed at
+/// startup are pinned
+#[derive(Debug)]
+pub struct AppletSpec {
+    pub name: String,
+    pub path: PathBuf,
+    pub settings: AppletSettings,
+}
+
+/// Read a manifest file and return the applets it declares
+pub fn load(path: &Path) -> Result<Vec<AppletSpec>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest '{}'", path.display()))?;
+    let manifest: Manifest = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse manifest '{}'", path.display()))?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+    manifest
+        .applets
+        .into_iter()
+        .map(|applet| {
+            if let Some(capabilities) = &applet.capabilities {
+                if let Some(unknown) = capabilities.iter().find(|c| !host::HOST_FUNCTIONS.contains(&c.as_str())) {
+                    return Err(anyhow!("Applet '{}' requests unknown capability '{}'", applet.name, unknown));
+                }
+            }
+
+            if let Some(key) = &applet.limits.rate_limit_key {
+                RateLimitKey::parse(key).map_err(|e| anyhow!("Applet '{}': {}", applet.name, e))?;
+            }
+            cors::validate(&applet.cors).map_err(|e| anyhow!("Applet '{}': {}", applet.name, e))?;
+
+            let mut wasi = applet.wasi;
+            for dir in &mut wasi.dirs {
+                dir.host = base.join(&dir.host);
+            }
+
+            Ok(AppletSpec {
+                name: applet.name,
+                path: base.join(applet.path),
+                settings: AppletSettings {
+                    aliases: applet.aliases,
+                    limits: applet.limits,
+                    capabilities: applet.capabilities,
+                    wasi,
+                    deterministic: applet.deterministic,
+                    allow_seed_header: applet.allow_seed_header,
+                    pinned: true,
+                    auth: applet.auth,
+                    cors: applet.cors,
+                    stream_body: applet.stream_body,
+                    websocket: applet.websocket,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Parse a `--load` value: `name=path`, or a bare path named after its file stem
+pub fn parse_load(value: &str) -> AppletSpec {
+    let (name, path) = match value.split_once('=') {
+        Some((name, path)) => (name.to_string(), PathBuf::from(path)),
+        None => {
+            let path = PathBuf::from(value);
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| value.to_string());
+            (name, path)
+        }
+    };
+    AppletSpec { name, path, settings: AppletSettings { pinned: true, ..Default::default() } }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::applet_store::AppletStore;
+
+/// Upper bounds (in seconds) of the latency and compile time histogram buckets
+const BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A cumulative histogram in the Prometheus style
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// Everything recorded for one applet
+#[derive(Clone, Debug, Default)]
+struct AppletMetrics {
+    requests: BTreeMap<u16, u64>, // Responses per status code
+    latency: Histogram,           // Time to serve a request
+    compile: Histogram,           // Time to compile the module
+    fuel_consumed: u64,           // Fuel burnt across all invocations
+    memory_high_water: u64,       // Largest linear memory seen, in bytes
+}
+
+#[derive(Default)]
+struct Registry {
+    applets: HashMap<Uuid, AppletMetrics>,
+    cache_hits: u64,
+    cache_misses: u64,
+    spans_dropped: u64, // Finished spans the export queue had no room for
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<Registry> {
+    REGISTRY.get_or_init(|| Mutex::new(Registry::default()))
+}
+
+/// Record a served request and how long it took
+pub fn record_request(applet: Uuid, status: u16, duration: Duration) {
+    let mut registry = registry().lock().unwrap();
+    let metrics = registry.applets.entry(applet).or_default();
+    *metrics.requests.entry(status).or_default() += 1;
+    metrics.latency.observe(duration);
+}
+
+/// Record how long an applet took to compile
+pub fn record_compile(applet: Uuid, duration: Duration) {
+    let mut registry = registry().lock().unwrap();
+    registry.applets.entry(applet).or_default().compile.observe(duration);
+}
+
+/// Record a lookup in the runner's executor cache
+pub fn record_cache_lookup(hit: bool) {
+    let mut registry = registry().lock().unwrap();
+    if hit {
+        registry.cache_hits += 1;
+    } else {
+        registry.cache_misses += 1;
+    }
+}
+
+/// Record a finished span dropped because the export queue was full
+pub fn record_dropped_span() {
+    registry().lock().unwrap().spans_dropped += 1;
+}
+
+/// Record the resources used by a single invocation
+pub fn record_execution(applet: Uuid, fuel_consumed: u64, memory_bytes: u64) {
+    let mut registry = registry().lock().unwrap();
+    let metrics = registry.applets.entry(applet).or_default();
+    metrics.fuel_consumed += fuel_consumed;
+    metrics.memory_high_water = metrics.memory_high_water.max(memory_bytes);
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render(store: &AppletStore) -> String {
+    let registry = registry().lock().unwrap();
+    let mut applets: Vec<_> = registry.applets.iter().collect();
+    applets.sort_by_key(|(uuid, _)| **uuid);
+
+    let mut out = String::new();
+
+    out.push_str("# HELP substrate_requests_total Requests served per applet and status code.\n");
+    out.push_str("# TYPE substrate_requests_total counter\n");
+    for (uuid, metrics) in &applets {
+        for (status, count) in &metrics.requests {
+            let _ = writeln!(
+                out,
+                "substrate_requests_total{{applet=\"{}\",status=\"{}\"}} {}",
+                uuid, status, count
+            );
+        }
+    }
+
+    out.push_str("# HELP substrate_request_duration_seconds Time taken to serve a request.\n");
+    out.push_str("# TYPE substrate_request_duration_seconds histogram\n");
+    for (uuid, metrics) in &applets {
+        if metrics.latency.count > 0 {
+            let labels = format!("applet=\"{}\"", uuid);
+            metrics.latency.render(&mut out, "substrate_request_duration_seconds", &labels);
+        }
+    }
+
+    out.push_str("# HELP substrate_compile_duration_seconds Time taken to compile an applet.\n");
+    out.push_str("# TYPE substrate_compile_duration_seconds histogram\n");
+    for (uuid, metrics) in &applets {
+        if metrics.compile.count > 0 {
+            let labels = format!("applet=\"{}\"", uuid);
+            metrics.compile.render(&mut out, "substrate_compile_duration_seconds", &labels);
+        }
+    }
+
+    out.push_str("# HELP substrate_fuel_consumed_total Fuel consumed by applet invocations.\n");
+    out.push_str("# TYPE substrate_fuel_consumed_total counter\n");
+    for (uuid, metrics) in &applets {
+        let _ = writeln!(out, "substrate_fuel_consumed_total{{applet=\"{}\"}} {}", uuid, metrics.fuel_consumed);
+    }
+
+    out.push_str("# HELP substrate_memory_high_water_bytes Largest linear memory an applet has used.\n");
+    out.push_str("# TYPE substrate_memory_high_water_bytes gauge\n");
+    for (uuid, metrics) in &applets {
+        let _ = writeln!(out, "substrate_memory_high_water_bytes{{applet=\"{}\"}} {}", uuid, metrics.memory_high_water);
+    }
+
+    out.push_str("# HELP substrate_executor_cache_lookups_total Executor cache lookups by result.\n");
+    out.push_str("# TYPE substrate_executor_cache_lookups_total counter\n");
+    let _ = writeln!(out, "substrate_executor_cache_lookups_total{{result=\"hit\"}} {}", registry.cache_hits);
+    let _ = writeln!(out, "substrate_executor_cache_lookups_total{{result=\"miss\"}} {}", registry.cache_misses);
+
+    out.push_str("# HELP substrate_spans_dropped_total Finished spans dropped because the export queue was full.\n");
+    out.push_str("# TYPE substrate_spans_dropped_total counter\n");
+    let _ = writeln!(out, "substrate_spans_dropped_total {}", registry.spans_dropped);
+
+    let (count, bytes) = store.usage();
+    out.push_str("# HELP substrate_store_applets Applets held in the applet store.\n");
+    out.push_str("# TYPE substrate_store_applets gauge\n");
+    let _ = writeln!(out, "substrate_store_applets {}", count);
+    out.push_str("# HELP substrate_store_bytes Total size of the wasm binaries in the applet store.\n");
+    out.push_str("# TYPE substrate_store_bytes gauge\n");
+    let _ = writeln!(out, "substrate_store_bytes {}", bytes);
+
+    out
+}
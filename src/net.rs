@@ -1,77 +1,1611 @@
-use warp::Filter;
-use warp::http::{Method, HeaderMap};
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+use warp::http::{Method, HeaderMap, HeaderValue, StatusCode};
+use warp::hyper::body::HttpBody;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
 use uuid::Uuid;
-use crate::{applet_store::AppletStore, runner::Runner, log, config};
-use crate::types::HttpRequest; // Import the custom request struct
+use crate::{admin, applet_store::AppletStore, determinism, events, executor, host, runner::Runner, log, config, metrics, record, throttle, tls, trace};
+use crate::auth::{AuthPolicy, Principal};
+use crate::config::{AdminListen, Limits};
+use crate::cors::CorsPolicy;
+use crate::log_sink::SinkKind;
+use crate::types::{BodyError, BodyStream, HttpRequest, HttpResponse, ResponseFrame, ResponseSink, StreamedHead}; // Import the custom request/response structs
 use bytes::Bytes;
 use std::net::IpAddr; // Import IpAddr
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-pub async fn start_server(store: Arc<AppletStore>) {
+/// Header carrying the request ID in both directions
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest caller-supplied request ID that is accepted as-is
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// How long interrupted guests get to unwind before the server stops waiting
+const INTERRUPT_WAIT: Duration = Duration::from_secs(5);
+
+/// Chunks of a streamed body buffered ahead of the guest
+const STREAM_CHUNKS: usize = 4;
+
+/// Most memory reserved up front for a body from its `Content-Length`
+const BODY_PREALLOCATE_MAX: usize = 1 << 20;
+
+/// WebSocket close codes (RFC 6455)
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_NO_STATUS: u16 = 1005; // The client's close frame carried no code
+const CLOSE_ABNORMAL: u16 = 1006; // The connection dropped without a close frame
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// How long a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Details of a client connection, attached to each of its requests
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub remote_addr: Option<SocketAddr>,
+    pub tls: bool,
+    pub client_cert: bool, // Client presented a certificate signed by the client CA
+    pub http2: bool,       // Requests arrive over HTTP/2, the only version that carries trailers
+}
+
+/// Serve applets until `shutdown` resolves, then stop accepting connections
+/// and drain: in-flight requests get the configured grace period, after
+/// which the guests still running are interrupted
+pub async fn start_server(
+    store: Arc<AppletStore>,
+    wasm_runner: Arc<Runner>,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
     // Access the global configuration
     let config = config::global_config();
 
-    let wasm_runner = Arc::new(Runner::new(store.clone()));
+    // Tells the listeners, and open WebSockets, that the server is stopping
+    let (stop, stop_signal) = tokio::sync::watch::channel(());
 
     // Define a route for handling all requests
     let handle_request = {
         let wasm_runner = wasm_runner.clone();
-    
-        warp::path::param::<Uuid>() // Match a UUID in the path
+        let store = store.clone();
+        let handle = {
+            let wasm_runner = wasm_runner.clone();
+            let store = store.clone();
+            Arc::new(move |uuid: Uuid,
+                           method: Method,
+                           headers: HeaderMap,
+                           cookies: Option<String>,
+                           full_path: warp::filters::path::FullPath,
+                           query_string: String, // Query string is now guaranteed
+                           remote_addr: Option<std::net::SocketAddr>,
+                           request_id: String,
+                           admission: Admission,
+                           response_sink: ResponseSink| {
+                // Open the request span, continuing the caller's trace if any
+                let mut span = trace::start_request(
+                    "http.request",
+                    headers.get("traceparent").and_then(|value| value.to_str().ok()),
+                );
+                span.set_attribute("http.method", &method);
+                span.set_attribute("http.target", full_path.as_str());
+                span.set_attribute("substrate.applet", uuid);
+
+                span.set_attribute("substrate.request_id", &request_id);
+                let access = AccessEntry {
+                    method: method.to_string(),
+                    path: full_path.as_str().to_string(),
+                    applet: uuid,
+                    request_id: request_id.clone(),
+                    remote_addr,
+                };
+
+                // Populate the custom HttpRequest struct; the body and the
+                // caller are filled in once the request is admitted
+                let request = HttpRequest {
+                    method,
+                    headers,
+                    cookies,
+                    path: full_path.as_str().to_string(),
+                    query: query_string, // Query string is now safe
+                    body: Bytes::new(),
+                    body_stream: None,
+                    remote_addr,
+                    request_id: request_id.clone(),
+                    principal: None,
+                    response_sink: Some(response_sink),
+                };
+
+                let started_at = admission.started_at;
+                let mut reply = run_guest(&store, &wasm_runner, uuid, request, admission, &mut span);
+                let elapsed = started_at.elapsed();
+
+                // Echo the request ID back to the caller
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+
+                metrics::record_request(uuid, reply.status().as_u16(), elapsed);
+                span.set_attribute("http.status_code", reply.status().as_u16());
+                access.log(&reply, elapsed);
+                reply
+            })
+        };
+
+        warp::path::param::<String>() // Match a UUID, name or alias in the path
             .and(warp::method()) // Capture the HTTP method
             .and(warp::header::headers_cloned()) // Clone all request headers
             .and(warp::header::optional("cookie")) // Capture the Cookie header if present
             .and(warp::path::full()) // Capture the full path
             .and(warp::query::raw().or(warp::any().map(|| "".to_string())).unify()) // Handle missing query strings
-            .and(warp::body::bytes()) // Capture the entire request body as raw bytes
-            .and(warp::filters::addr::remote()) // Capture the remote client's IP address
-            .map(
-                move |uuid: Uuid,
+            .and(warp::body::stream()) // Read the body only once the applet's limits are known
+            .and(remote_addr()) // Capture the remote client's IP address
+            .and(http2()) // Whether trailers can reach the client
+            .and_then(
+                move |applet_id: String,
                       method: Method,
                       headers: HeaderMap,
                       cookies: Option<String>,
                       full_path: warp::filters::path::FullPath,
-                      query_string: String, // Query string is now guaranteed
-                      body: Bytes,
-                      remote_addr: Option<std::net::SocketAddr>| {
-                    // Populate the custom HttpRequest struct
-                    let request = HttpRequest {
-                        method,
-                        headers,
-                        cookies,
-                        path: full_path.as_str().to_string(),
-                        query: query_string, // Query string is now safe
-                        body,
-                        remote_addr,
-                    };
-    
-                    // Delegate to the WASM runner
-                    match wasm_runner.run(uuid, request) {
-                        Ok(response) => warp::reply::with_status(
-                            warp::reply::json(&response),
-                            warp::http::StatusCode::OK,
-                        ),
-                        Err(err) => warp::reply::with_status(
-                            warp::reply::json(&err),
-                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        ),
+                      query_string: String,
+                      body,
+                      remote_addr: Option<std::net::SocketAddr>,
+                      http2: bool| {
+                    let handle = handle.clone();
+                    let store = store.clone();
+                    let wasm_runner = wasm_runner.clone();
+                    async move {
+                        let received_at = Instant::now();
+
+                        // Find the applet the path refers to
+                        let Some(uuid) = store.resolve(&applet_id) else {
+                            return Ok::<_, Infallible>(not_found_reply(&applet_id));
+                        };
+
+                        // Preflights carry no credentials, so they are answered
+                        // before the request is admitted and authenticated
+                        let cors = cors_policy(&store, uuid);
+                        if let Some(reply) = cors.preflight(&method, &headers) {
+                            let access = AccessEntry {
+                                method: method.to_string(),
+                                path: full_path.as_str().to_string(),
+                                applet: uuid,
+                                request_id: request_id(&headers),
+                                remote_addr,
+                            };
+                            return Ok(preflight_reply(reply, access, received_at.elapsed()));
+                        }
+                        let origin = headers.get(warp::http::header::ORIGIN).cloned();
+                        let limits = wasm_runner.limits_for(uuid);
+                        let head = RequestHead::new(&method, full_path.as_str(), &query_string, &headers, remote_addr);
+                        let admission = admit(&store, uuid, &limits, head, body).await;
+
+                        // Accept the caller's request ID or assign a new one
+                        let request_id = request_id(&headers);
+
+                        // Guests run on the blocking pool so a slow one cannot
+                        // stall the async workers (or signal handling). A
+                        // guest that streams its response has it sent while
+                        // it is still running.
+                        let (response_sink, mut streamed) = ResponseSink::new(STREAM_CHUNKS, http2);
+                        let mut task = {
+                            let request_id = request_id.clone();
+                            tokio::task::spawn_blocking(move || {
+                                handle(
+                                    uuid,
+                                    method,
+                                    headers,
+                                    cookies,
+                                    full_path,
+                                    query_string,
+                                    remote_addr,
+                                    request_id,
+                                    admission,
+                                    response_sink,
+                                )
+                            })
+                        };
+                        let mut reply = tokio::select! {
+                            Ok(head) = &mut streamed => streamed_reply(head, &request_id),
+                            reply = &mut task => match streamed.try_recv() {
+                                Ok(head) => streamed_reply(head, &request_id),
+                                Err(_) => reply.unwrap_or_else(|e| {
+                                    error_reply(&anyhow::anyhow!("Request handler failed: {}", e))
+                                }),
+                            },
+                        };
+                        cors.apply(origin.as_ref(), &mut reply);
+                        Ok(reply)
+                    }
+                },
+            )
+    };
+
+    // Serve upgrade requests to applets that accept WebSockets; anything
+    // else falls through to the request handler
+    let socket_route = {
+        let store = store.clone();
+        let wasm_runner = wasm_runner.clone();
+        let stop_signal = stop_signal.clone();
+        warp::path::param::<String>()
+            .and(warp::ws())
+            .and(warp::header::headers_cloned())
+            .and(warp::header::optional("cookie"))
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(|| "".to_string())).unify())
+            .and(remote_addr())
+            .and_then(
+                move |applet_id: String,
+                      ws: warp::ws::Ws,
+                      headers: HeaderMap,
+                      cookies: Option<String>,
+                      full_path: warp::filters::path::FullPath,
+                      query_string: String,
+                      remote_addr: Option<SocketAddr>| {
+                    let store = store.clone();
+                    let wasm_runner = wasm_runner.clone();
+                    let stopped = stopped(&stop_signal);
+                    async move {
+                        let uuid = store.resolve(&applet_id).filter(|uuid| {
+                            store.metadata(uuid).is_some_and(|metadata| metadata.settings.websocket)
+                        });
+                        let Some(uuid) = uuid else {
+                            return Err(warp::reject::not_found());
+                        };
+                        let limits = wasm_runner.limits_for(uuid);
+                        let head =
+                            RequestHead::new(&Method::GET, full_path.as_str(), &query_string, &headers, remote_addr);
+                        let admission = admit_without_body(&store, uuid, &limits, head).await;
+                        let cors = cors_policy(&store, uuid);
+                        let request =
+                            SocketRequest { uuid, headers, cookies, full_path, query_string, remote_addr, cors };
+                        Ok(accept_socket(ws, wasm_runner, request, admission, stopped))
                     }
                 },
             )
-    };    
+    };
+
+    // Stream the events applets publish to their channels. The applet is part
+    // of the path, `/_events/<applet>/<channel>`: channels are per applet, and
+    // its auth policy decides who may subscribe
+    let events_route = {
+        let store = store.clone();
+        let wasm_runner = wasm_runner.clone();
+        let stop_signal = stop_signal.clone();
+        warp::path!("_events" / String / String)
+            .and(warp::get())
+            .and(warp::header::headers_cloned())
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(|| "".to_string())).unify())
+            .and(remote_addr())
+            .and_then(
+                move |applet_id: String,
+                      channel: String,
+                      headers: HeaderMap,
+                      full_path: warp::filters::path::FullPath,
+                      query_string: String,
+                      remote_addr: Option<SocketAddr>| {
+                    let store = store.clone();
+                    let wasm_runner = wasm_runner.clone();
+                    let stopped = stopped(&stop_signal);
+                    async move {
+                        let Some(uuid) = store.resolve(&applet_id) else {
+                            return Ok::<_, Infallible>(not_found_reply(&applet_id));
+                        };
+                        let cors = cors_policy(&store, uuid);
+                        let origin = headers.get(warp::http::header::ORIGIN).cloned();
+                        // Subscribers run no guest, so they are not held to the
+                        // applet's concurrency cap; the rate limit still applies
+                        let limits = Limits { max_concurrency: 0, ..wasm_runner.limits_for(uuid) };
+                        let head =
+                            RequestHead::new(&Method::GET, full_path.as_str(), &query_string, &headers, remote_addr);
+                        let admission = admit_without_body(&store, uuid, &limits, head).await;
+                        let request = SubscribeRequest { uuid, channel, headers, full_path, query_string, remote_addr };
+                        let mut reply = subscribe_events(request, admission, stopped);
+                        cors.apply(origin.as_ref(), &mut reply);
+                        Ok(reply)
+                    }
+                },
+            )
+    };
+
+    // Expose metrics in the Prometheus text format
+    let metrics_route = {
+        let store = store.clone();
+        warp::path!("metrics").and(warp::get()).map(move || {
+            warp::reply::with_header(
+                metrics::render(&store),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+            .into_response()
+        })
+    };
+
+    // Probes for load balancers and build information
+    let health_routes = healthz().or(readyz(store.clone(), wasm_runner.clone())).unify().or(version()).unify();
 
     // Parse the host string into an IpAddr
     let host: IpAddr = config.host.parse().expect("Invalid host");
 
+    let health_routes = health_routes.boxed();
+    let admin_routes = metrics_route.or(admin::routes(store.clone(), wasm_runner.clone())).unify().boxed();
+    let applet_routes = socket_route.or(handle_request.map(Reply::into_response)).unify().boxed();
+    let applet_routes = events_route.or(applet_routes).unify().boxed();
+    let (public_routes, admin_routes) =
+        split_routes(health_routes, admin_routes, applet_routes, config.admin_listen.is_some());
+    let admin_listener = config.admin_listen.as_ref().zip(admin_routes);
+
     // Start the server with the parsed host and port from the configuration
+    let stopped = move || stopped(&stop_signal);
+    let mut servers = Vec::new();
+    let scheme = if config.tls.is_some() { "https" } else { "http" };
+    let (addr, server) = listen_tcp(public_routes, (host, config.port).into(), stopped())
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}:{}: {}", host, config.port, e))?;
+    log::log("substrate", &format!("Substrate server running at {}://{}", scheme, addr));
+    servers.push(server);
+    if let Some((listen, routes)) = admin_listener {
+        let started = match listen {
+            AdminListen::Tcp(addr) => listen_tcp(routes, *addr, stopped())
+                .await
+                .map(|(addr, server)| (format!("{}://{}", scheme, addr), server)),
+            AdminListen::Unix(path) => {
+                listen_unix(routes, path, stopped()).map(|server| (listen.to_string(), server))
+            }
+        };
+        let (addr, server) =
+            started.map_err(|e| anyhow::anyhow!("Failed to start admin listener on {}: {}", listen, e))?;
+        log::log("substrate", &format!("Admin listener running at {}", addr));
+        servers.push(server);
+    }
+
+    let mut server = tokio::spawn(futures_util::future::join_all(servers));
+    tokio::select! {
+        _ = &mut server => return Ok(()),
+        _ = shutdown => {}
+    }
+
+    // Stop accepting connections and let in-flight requests finish
     log::log(
         "substrate",
         &format!(
-            "Substrate server running at http://{}:{}",
-            host, config.port
+            "No longer accepting connections; waiting up to {}s for {} in-flight requests",
+            config.shutdown_grace.as_secs(),
+            executor::in_flight()
         ),
     );
-    warp::serve(handle_request)
-        .run((host, config.port))
+    let _ = stop.send(());
+    if tokio::time::timeout(config.shutdown_grace, &mut server).await.is_ok() {
+        return Ok(());
+    }
+
+    // Out of time: trap the guests still running and give them a moment to
+    // unwind. Connections that stay open, such as log tails, are dropped.
+    log::log(
+        "substrate",
+        &format!("Grace period over; interrupting {} running guests", executor::in_flight()),
+    );
+    executor::interrupt_all();
+    let deadline = Instant::now() + INTERRUPT_WAIT;
+    while executor::in_flight() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(())
+}
+
+/// An upgrade request to an applet that accepts WebSockets
+struct SocketRequest {
+    uuid: Uuid,
+    headers: HeaderMap,
+    cookies: Option<String>,
+    full_path: warp::filters::path::FullPath,
+    query_string: String,
+    remote_addr: Option<SocketAddr>,
+    cors: CorsPolicy, // Checked against the page's origin before upgrading
+}
+
+/// Check the origin, then admit and authenticate an upgrade request like
+/// any other, switch protocols and serve the connection in the background
+fn accept_socket(
+    ws: warp::ws::Ws,
+    runner: Arc<Runner>,
+    socket: SocketRequest,
+    admission: Admission,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> warp::reply::Response {
+    let SocketRequest { uuid, headers, cookies, full_path, query_string, remote_addr, cors } = socket;
+    let mut span = trace::start_request(
+        "websocket.upgrade",
+        headers.get("traceparent").and_then(|value| value.to_str().ok()),
+    );
+    span.set_attribute("http.target", full_path.as_str());
+    span.set_attribute("substrate.applet", uuid);
+    let request_id = request_id(&headers);
+    span.set_attribute("substrate.request_id", &request_id);
+    let access = AccessEntry {
+        method: Method::GET.to_string(),
+        path: full_path.as_str().to_string(),
+        applet: uuid,
+        request_id: request_id.clone(),
+        remote_addr,
+    };
+
+    let started_at = admission.started_at;
+    let limits = runner.limits_for(uuid);
+    let admitted = match cors.allows_socket(headers.get(warp::http::header::ORIGIN)) {
+        true => admission.admitted(&Method::GET, full_path.as_str(), &query_string, &headers, &mut span),
+        false => Err(Box::new(crate::cors::forbidden())),
+    };
+    let mut reply = match admitted {
+        Ok(admitted) => {
+            let request = HttpRequest {
+                method: Method::GET,
+                headers,
+                cookies,
+                path: full_path.as_str().to_string(),
+                query: query_string,
+                body: Bytes::new(),
+                body_stream: None,
+                remote_addr,
+                request_id: request_id.clone(),
+                principal: admitted.principal,
+                response_sink: None,
+            };
+            let permit = admitted.permit;
+            let ws = match limits.max_body_bytes {
+                0 => ws,
+                max => ws.max_message_size(max).max_frame_size(max),
+            };
+            ws.on_upgrade(move |socket| serve_socket(socket, runner, uuid, request, limits, permit, stopped))
+                .into_response()
+        }
+        Err(reply) => *reply,
+    };
+    let elapsed = started_at.elapsed();
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    metrics::record_request(uuid, reply.status().as_u16(), elapsed);
+    span.set_attribute("http.status_code", reply.status().as_u16());
+    access.log(&reply, elapsed);
+    reply
+}
+
+/// Serve an upgraded connection from one guest instance: `on_open`, then
+/// `on_message` for each text or binary message, then `on_close`. The
+/// connection counts against the applet's concurrency cap while open, and
+/// is closed when it idles or outlives the applet's limits, or when the
+/// server stops.
+async fn serve_socket(
+    socket: warp::ws::WebSocket,
+    runner: Arc<Runner>,
+    uuid: Uuid,
+    request: HttpRequest,
+    limits: Limits,
+    _permit: throttle::Permit,
+    stopped: impl Future<Output = ()>,
+) {
+    use futures_util::{SinkExt, StreamExt};
+    use warp::ws::Message;
+
+    let (mut outgoing, mut incoming) = socket.split();
+    let (frames, mut queued) = tokio::sync::mpsc::channel::<Message>(STREAM_CHUNKS);
+
+    // Frames from the guest and the host's close frames go out in order;
+    // nothing is sent after a close frame
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = queued.recv().await {
+            let close = frame.is_close();
+            if outgoing.send(frame).await.is_err() || close {
+                break;
+            }
+        }
+        let _ = outgoing.close().await;
+    });
+
+    // Waits for a client that is behind like the guest's frames do
+    let close = |code: u16, reason: &'static str| {
+        let frame = Message::close_with(code, reason);
+        let frames = &frames;
+        let wait = limits.client_wait();
+        async move {
+            match wait {
+                Some(wait) => drop(frames.send_timeout(frame, wait).await),
+                None => drop(frames.send(frame).await),
+            }
+        }
+    };
+    let failed = |e: anyhow::Error| {
+        log::log_with(
+            "substrate",
+            log::Level::Error,
+            &format!("WebSocket for applet {} failed: {:#}", uuid, e),
+            &[],
+        );
+        close(CLOSE_INTERNAL_ERROR, "Internal error")
+    };
+
+    let opened = {
+        let frames = frames.clone();
+        on_blocking_pool(move || {
+            let mut session = runner.open_socket(uuid, request, frames)?;
+            session.on_open()?;
+            Ok(session)
+        })
+        .await
+    };
+    let mut session = match opened {
+        Ok(session) => session,
+        Err(e) => {
+            failed(e).await;
+            drop(frames);
+            let _ = writer.await;
+            return;
+        }
+    };
+
+    let idle_timeout = Duration::from_secs(limits.ws_idle_timeout);
+    let lifetime = async {
+        match limits.ws_max_lifetime {
+            0 => std::future::pending().await,
+            secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+        }
+    };
+    tokio::pin!(lifetime, stopped);
+    let code = loop {
+        let next = async {
+            match limits.ws_idle_timeout {
+                0 => Some(incoming.next().await),
+                _ => tokio::time::timeout(idle_timeout, incoming.next()).await.ok(),
+            }
+        };
+        let message = tokio::select! {
+            message = next => message,
+            _ = &mut lifetime => {
+                close(CLOSE_NORMAL, "Connection lifetime exceeded").await;
+                break CLOSE_NORMAL;
+            }
+            _ = &mut stopped => {
+                close(CLOSE_GOING_AWAY, "Server stopping").await;
+                break CLOSE_GOING_AWAY;
+            }
+        };
+        let message = match message {
+            None => {
+                close(CLOSE_NORMAL, "Idle timeout").await;
+                break CLOSE_NORMAL;
+            }
+            Some(Some(Ok(message))) => message,
+            Some(_) => break CLOSE_ABNORMAL, // Dropped without a close frame
+        };
+        if message.is_close() {
+            break message.close_frame().map_or(CLOSE_NO_STATUS, |(code, _)| code);
+        }
+        if !message.is_text() && !message.is_binary() {
+            continue; // Pings are answered by the connection itself
+        }
+        let text = message.is_text();
+        let received = on_blocking_pool(move || {
+            session.on_message(Bytes::from(message.into_bytes()), text)?;
+            Ok(session)
+        })
         .await;
+        session = match received {
+            Ok(session) => session,
+            Err(e) => {
+                failed(e).await;
+                drop(frames);
+                let _ = writer.await;
+                return;
+            }
+        };
+    };
+
+    if let Err(e) = on_blocking_pool(move || session.on_close(code)).await {
+        log::log_with(
+            "substrate",
+            log::Level::Error,
+            &format!("WebSocket for applet {} failed to close: {:#}", uuid, e),
+            &[],
+        );
+    }
+    drop(frames);
+    let _ = writer.await;
+}
+
+/// A subscription to one of an applet's event channels
+struct SubscribeRequest {
+    uuid: Uuid,
+    channel: String,
+    headers: HeaderMap,
+    full_path: warp::filters::path::FullPath,
+    query_string: String,
+    remote_addr: Option<SocketAddr>,
+}
+
+/// Admit and authenticate a subscriber under the applet's policy, then
+/// stream the channel as server-sent events: the events missed since
+/// `Last-Event-ID` first, then new ones until the server stops. A
+/// subscriber that falls too far behind is disconnected and catches up on
+/// reconnecting. Subscriptions count against the applet's rate limit but
+/// not its concurrency cap, since no guest runs.
+fn subscribe_events(
+    subscription: SubscribeRequest,
+    admission: Admission,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> warp::reply::Response {
+    use futures_util::StreamExt;
+
+    let SubscribeRequest { uuid, channel, headers, full_path, query_string, remote_addr } = subscription;
+    let mut span = trace::start_request(
+        "events.subscribe",
+        headers.get("traceparent").and_then(|value| value.to_str().ok()),
+    );
+    span.set_attribute("http.target", full_path.as_str());
+    span.set_attribute("substrate.applet", uuid);
+    let request_id = request_id(&headers);
+    span.set_attribute("substrate.request_id", &request_id);
+    let access = AccessEntry {
+        method: Method::GET.to_string(),
+        path: full_path.as_str().to_string(),
+        applet: uuid,
+        request_id: request_id.clone(),
+        remote_addr,
+    };
+
+    let started_at = admission.started_at;
+    let mut reply = match admission.admitted(&Method::GET, full_path.as_str(), &query_string, &headers, &mut span) {
+        Err(reply) => *reply,
+        Ok(_) if !events::valid_channel(&channel) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": format!("Invalid channel name: '{}'", channel) })),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response(),
+        Ok(_) => {
+            let last_event_id = headers
+                .get("last-event-id")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok());
+            let (missed, receiver) = events::subscribe(uuid, &channel, last_event_id);
+            let published = futures_util::stream::unfold(receiver, |mut receiver| async move {
+                // Lagging ends the stream so the client reconnects from its last event
+                let event = receiver.recv().await.ok()?;
+                Some((event, receiver))
+            });
+            let stream = futures_util::stream::iter(missed)
+                .chain(published)
+                .map(|event| {
+                    let mut sse = warp::sse::Event::default().id(event.id.to_string()).data(event.data.as_str());
+                    if let Some(name) = &event.event {
+                        sse = sse.event(name.as_str());
+                    }
+                    Ok::<_, Infallible>(sse)
+                })
+                .take_until(stopped);
+            warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+        }
+    };
+    let elapsed = started_at.elapsed();
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    metrics::record_request(uuid, reply.status().as_u16(), elapsed);
+    span.set_attribute("http.status_code", reply.status().as_u16());
+    access.log(&reply, elapsed);
+    reply
+}
+
+/// Run a guest callback on the blocking pool
+async fn on_blocking_pool<T: Send + 'static>(callback: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(callback)
+        .await
+        .map_err(|e| anyhow::anyhow!("WebSocket handler failed: {}", e))?
+}
+
+/// Resolves once the server starts stopping
+fn stopped(signal: &tokio::sync::watch::Receiver<()>) -> impl Future<Output = ()> + Send + 'static {
+    let mut signal = signal.clone();
+    async move {
+        let _ = signal.changed().await;
+    }
+}
+
+/// Outcome of the checks made before a request reaches the guest
+struct Admission {
+    started_at: Instant,
+    policy: AuthPolicy,                    // How the caller must authenticate
+    permit: Result<throttle::Permit, throttle::Throttled>,
+    principal: Option<anyhow::Result<Option<Principal>>>, // Set when verified before the body was read
+    body: Result<(Bytes, Option<BodyStream>), Refused>, // Buffered body, or the start of a stream
+}
+
+/// The parts of a request admission looks at before reading the body
+struct RequestHead<'a> {
+    method: &'a Method,
+    path_and_query: String, // As signed by HMAC callers
+    headers: &'a HeaderMap,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<'a> RequestHead<'a> {
+    fn new(
+        method: &'a Method,
+        path: &str,
+        query: &str,
+        headers: &'a HeaderMap,
+        remote_addr: Option<SocketAddr>,
+    ) -> Self {
+        RequestHead { method, path_and_query: path_and_query(path, query), headers, remote_addr }
+    }
+}
+
+/// The request target as clients sign it: the path, then the query if any
+fn path_and_query(path: &str, query: &str) -> String {
+    match query.is_empty() {
+        true => path.to_string(),
+        false => format!("{}?{}", path, query),
+    }
+}
+
+/// What a request that passed admission and authentication runs with
+struct Admitted {
+    permit: throttle::Permit,
+    body: Bytes,
+    body_stream: Option<BodyStream>,
+    principal: Option<Principal>, // Verified caller, when the applet requires authentication
+}
+
+impl Admission {
+    /// Turn the request away if it was throttled or refused, or its caller
+    /// fails to authenticate; otherwise hand over what it runs with
+    fn admitted(
+        self,
+        method: &Method,
+        path: &str,
+        query_string: &str,
+        headers: &HeaderMap,
+        span: &mut trace::Span,
+    ) -> Result<Admitted, Box<warp::reply::Response>> {
+        // Requests turned away before the body was read
+        let permit = self.permit.map_err(|throttled| Box::new(throttled_reply(&throttled)))?;
+        let (body, body_stream) = self.body.map_err(|refused| Box::new(refused_reply(&refused)))?;
+
+        // Verify the caller before the guest sees the request, unless that
+        // was done before the body was read
+        let principal = match self.principal {
+            Some(verified) => verified,
+            None => config::global_config().auth.authenticate(
+                self.policy,
+                method,
+                &path_and_query(path, query_string),
+                headers,
+                &body,
+            ),
+        };
+        let principal = principal.map_err(|err| {
+            span.set_attribute("substrate.auth_error", err.to_string());
+            Box::new(unauthorized_reply(self.policy, &err))
+        })?;
+        if let Some(principal) = &principal {
+            span.set_attribute("substrate.principal", &principal.id);
+        }
+        Ok(Admitted { permit, body, body_stream, principal })
+    }
+}
+
+/// Admit and authenticate the request, then run it through the applet's guest
+fn run_guest(
+    store: &AppletStore,
+    runner: &Runner,
+    uuid: Uuid,
+    mut request: HttpRequest,
+    admission: Admission,
+    span: &mut trace::Span,
+) -> warp::reply::Response {
+    let admitted = match admission.admitted(&request.method, &request.path, &request.query, &request.headers, span) {
+        Ok(admitted) => admitted,
+        Err(reply) => return *reply,
+    };
+    let _permit = admitted.permit;
+    request.body = admitted.body;
+    request.body_stream = admitted.body_stream;
+    request.principal = admitted.principal;
+
+    // Delegate to the WASM runner
+    let recorder = record::Recorder::start(store, uuid, &request);
+    let result = runner.run(uuid, request);
+    if let Some(recorder) = recorder {
+        recorder.finish(&result);
+    }
+    match result {
+        Ok(response) => into_reply(response),
+        Err(err) => match err.downcast_ref::<BodyError>() {
+            // The guest read past the body limit, or the client went away
+            Some(e) => refused_reply(&Refused::Body(e.clone())),
+            None => {
+                span.set_error(&err);
+                error_reply(&err)
+            }
+        },
+    }
+}
+
+/// Why a request was refused before the guest ran
+enum Refused {
+    HeadersTooLarge(usize), // Over the applet's header limit, in bytes
+    InvalidSeed(String),    // Malformed `x-substrate-seed` header
+    Body(BodyError),
+}
+
+/// Apply the rate limit and concurrency cap in `limits`, check the
+/// requested seed, if the applet honours one, and verify the caller, then
+/// apply the header and body limits. The body is buffered, or for applets
+/// that stream it, handed over as it arrives. Applets verifying HMAC
+/// signatures always buffer it and verify the caller afterwards, since the
+/// signature covers the body.
+async fn admit(
+    store: &AppletStore,
+    uuid: Uuid,
+    limits: &Limits,
+    head: RequestHead<'_>,
+    body: impl futures_util::Stream<Item = Result<impl warp::Buf + Send, warp::Error>> + Send + Unpin + 'static,
+) -> Admission {
+    let started_at = Instant::now();
+    let settings = store.metadata(&uuid).map(|metadata| metadata.settings).unwrap_or_default();
+    let policy = settings.auth.unwrap_or(config::global_config().auth.applet_policy);
+    let headers = head.headers;
+
+    let permit = throttle::admit(uuid, limits, headers, head.remote_addr);
+    let mut principal = None;
+    let body = if permit.is_err() {
+        Ok((Bytes::new(), None))
+    } else if let Some(Err(e)) = settings.allow_seed_header.then(|| determinism::requested_seed(headers)) {
+        Err(Refused::InvalidSeed(e.to_string()))
+    } else if policy == AuthPolicy::Hmac {
+        receive_body(headers, limits, false, body).await
+    } else {
+        // Unauthenticated clients are turned away without the server
+        // buffering whatever they send
+        let auth = &config::global_config().auth;
+        let verified = auth.authenticate(policy, head.method, &head.path_and_query, headers, &[]);
+        let body = match verified {
+            Ok(_) => receive_body(headers, limits, settings.stream_body, body).await,
+            Err(_) => Ok((Bytes::new(), None)),
+        };
+        principal = Some(verified);
+        body
+    };
+    Admission { started_at, policy, permit, principal, body }
+}
+
+/// `admit` for requests without a body to read: WebSocket upgrades and
+/// event subscriptions
+async fn admit_without_body(store: &AppletStore, uuid: Uuid, limits: &Limits, head: RequestHead<'_>) -> Admission {
+    admit(store, uuid, limits, head, futures_util::stream::empty::<Result<Bytes, warp::Error>>()).await
+}
+
+async fn receive_body(
+    headers: &HeaderMap,
+    limits: &Limits,
+    stream: bool,
+    mut body: impl futures_util::Stream<Item = Result<impl warp::Buf + Send, warp::Error>> + Send + Unpin + 'static,
+) -> Result<(Bytes, Option<BodyStream>), Refused> {
+    use futures_util::StreamExt;
+
+    let header_bytes: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+    if limits.max_header_bytes > 0 && header_bytes > limits.max_header_bytes {
+        return Err(Refused::HeadersTooLarge(limits.max_header_bytes));
+    }
+    let max_body = limits.max_body_bytes;
+    let content_length = headers
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if max_body > 0 && content_length.is_some_and(|length| length > max_body) {
+        return Err(Refused::Body(BodyError::TooLarge(max_body)));
+    }
+    let too_large = move |received: usize| max_body > 0 && received > max_body;
+
+    if stream {
+        // Bounded so a guest that reads slowly applies backpressure to the client
+        let (chunks, receiver) = tokio::sync::mpsc::channel(STREAM_CHUNKS);
+        tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(mut chunk) => chunk.copy_to_bytes(chunk.remaining()),
+                    Err(e) => {
+                        let _ = chunks.send(Err(BodyError::Failed(e.to_string()))).await;
+                        return;
+                    }
+                };
+                received += chunk.len();
+                if too_large(received) {
+                    let _ = chunks.send(Err(BodyError::TooLarge(max_body))).await;
+                    return;
+                }
+                if chunks.send(Ok(chunk)).await.is_err() {
+                    return; // The guest finished without reading the rest
+                }
+            }
+        });
+        return Ok((Bytes::new(), Some(BodyStream::new(receiver, limits.client_wait()))));
+    }
+
+    let mut buffered = bytes::BytesMut::with_capacity(content_length.unwrap_or(0).min(BODY_PREALLOCATE_MAX));
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| Refused::Body(BodyError::Failed(e.to_string())))?;
+        if too_large(buffered.len() + chunk.remaining()) {
+            return Err(Refused::Body(BodyError::TooLarge(max_body)));
+        }
+        buffered.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok((buffered.freeze(), None))
+}
+
+/// Routes served on one listener
+type Routes = BoxedFilter<(warp::reply::Response,)>;
+
+/// Routes for the public listener, and for the admin listener when admin,
+/// metrics and log routes are given their own; otherwise they share the
+/// public one. Probes are answered on both.
+fn split_routes(health: Routes, admin: Routes, applets: Routes, separate_admin: bool) -> (Routes, Option<Routes>) {
+    match separate_admin {
+        true => (health.clone().or(applets).unify().boxed(), Some(health.or(admin).unify().boxed())),
+        false => (health.or(admin).unify().or(applets).unify().boxed(), None),
+    }
+}
+
+/// A running listener; resolves once it has stopped
+type Server = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Serve `routes` on a TCP address, over TLS when it is configured
+async fn listen_tcp(
+    routes: Routes,
+    addr: SocketAddr,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<(SocketAddr, Server)> {
+    if config::global_config().tls.is_some() {
+        return serve_tls(routes, addr, stopped).await;
+    }
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let incoming = futures_util::stream::unfold(listener, move |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => accept_failed(addr, e).await,
+            }
+        }
+    });
+    let server = serve_connections(
+        routes,
+        incoming,
+        |stream: &tokio::net::TcpStream| Connection {
+            remote_addr: stream.peer_addr().ok(),
+            tls: false,
+            client_cert: false,
+            http2: false,
+        },
+        stopped,
+    );
+    Ok((addr, server))
+}
+
+/// Log a failed accept and pause before the next. Errors such as running
+/// out of file descriptors persist for a while; retrying at once would spin.
+async fn accept_failed(listener: impl std::fmt::Display, e: std::io::Error) {
+    log::log_with(
+        "substrate",
+        log::Level::Error,
+        &format!("Failed to accept a connection on {}: {}", listener, e),
+        &[],
+    );
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
+
+/// Serve `routes` over TLS. Handshakes use the certificates loaded at the
+/// time the connection is accepted, so reloads apply to new connections.
+async fn serve_tls(
+    routes: Routes,
+    addr: SocketAddr,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<(SocketAddr, Server)> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+
+    // Accept and handshake in the background; the server takes finished
+    // connections and stops accepting by dropping the receiver
+    let (connections, mut accepted) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        accept_failed(addr, e).await;
+                        continue;
+                    }
+                },
+                _ = connections.closed() => return,
+            };
+            let Some(acceptor) = tls::acceptor() else {
+                return;
+            };
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = connections.send(stream).await;
+                    }
+                    Ok(Err(e)) => log::log_with(
+                        "substrate",
+                        log::Level::Debug,
+                        &format!("TLS handshake with {} failed: {}", remote_addr, e),
+                        &[],
+                    ),
+                    Err(_) => log::log_with(
+                        "substrate",
+                        log::Level::Debug,
+                        &format!("TLS handshake with {} timed out", remote_addr),
+                        &[],
+                    ),
+                }
+            });
+        }
+    });
+    let incoming = futures_util::stream::poll_fn(move |cx| accepted.poll_recv(cx).map(|stream| stream.map(Ok)));
+
+    let server = serve_connections(
+        routes,
+        incoming,
+        |stream: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
+            let (tcp, session) = stream.get_ref();
+            Connection {
+                remote_addr: tcp.peer_addr().ok(),
+                tls: true,
+                client_cert: session.peer_certificates().is_some(),
+                http2: false,
+            }
+        },
+        stopped,
+    );
+    Ok((addr, server))
+}
+
+/// Serve `routes` on a Unix domain socket, replacing a stale socket file.
+/// Access is governed by the socket's file permissions.
+#[cfg(unix)]
+fn listen_unix(routes: Routes, path: &Path, stopped: impl Future<Output = ()> + Send + 'static) -> anyhow::Result<Server> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    let name = format!("unix:{}", path.display());
+    let incoming = futures_util::stream::unfold((listener, name), |(listener, name)| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), (listener, name))),
+                Err(e) => accept_failed(&name, e).await,
+            }
+        }
+    });
+    let server = serve_connections(
+        routes,
+        incoming,
+        |_: &tokio::net::UnixStream| Connection { remote_addr: None, tls: false, client_cert: false, http2: false },
+        stopped,
+    );
+    let path = path.to_path_buf();
+    Ok(Box::pin(async move {
+        server.await;
+        let _ = std::fs::remove_file(path);
+    }))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_: Routes, _: &Path, _: impl Future<Output = ()> + Send + 'static) -> anyhow::Result<Server> {
+    Err(anyhow::anyhow!("Unix domain sockets are not supported on this platform"))
+}
+
+/// Serve `routes` on already accepted connections, attaching the
+/// `Connection` that `describe` builds to each of their requests along
+/// with the HTTP version the request came over
+fn serve_connections<S, IO>(
+    routes: Routes,
+    incoming: S,
+    describe: fn(&IO) -> Connection,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> Server
+where
+    S: futures_util::Stream<Item = std::io::Result<IO>> + Send + 'static,
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use warp::hyper::service::{make_service_fn, service_fn, Service};
+
+    let make_service = make_service_fn(move |stream: &IO| {
+        let connection = describe(stream);
+        let service = warp::service(routes.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: warp::hyper::Request<warp::hyper::Body>| {
+                let http2 = request.version() == warp::http::Version::HTTP_2;
+                request.extensions_mut().insert(Connection { http2, ..connection.clone() });
+                service.clone().call(request)
+            }))
+        }
+    });
+    let server = warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(stopped);
+    Box::pin(async move {
+        if let Err(e) = server.await {
+            log::log_with("substrate", log::Level::Error, &format!("Server error: {}", e), &[]);
+        }
+    })
+}
+
+/// The client's address, when the connection has one
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<Connection>().map(|connection: Option<Connection>| {
+        connection.and_then(|connection| connection.remote_addr)
+    })
+}
+
+/// Whether the request came over HTTP/2
+fn http2() -> impl Filter<Extract = (bool,), Error = Infallible> + Clone {
+    warp::ext::optional::<Connection>().map(|connection: Option<Connection>| connection.is_some_and(|c| c.http2))
+}
+
+/// `GET /healthz`: the process is up and serving
+fn healthz() -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })).into_response())
+}
+
+/// `GET /readyz`: 200 once the store is loaded, every pinned applet is
+/// compiled and the storage backends are reachable, 503 otherwise
+fn readyz(
+    store: Arc<AppletStore>,
+    runner: Arc<Runner>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("readyz").and(warp::get()).map(move || {
+        let config = config::global_config();
+        let applets = store.list();
+        let pinned: Vec<_> = applets.iter().filter(|(_, metadata)| metadata.settings.pinned).collect();
+        let pending: Vec<_> = pinned
+            .iter()
+            .filter(|(uuid, _)| !runner.is_compiled(uuid))
+            .map(|(_, metadata)| metadata.name.clone())
+            .collect();
+        let store_loaded = !applets.is_empty() || config.admin_enabled || config.watch_dir.is_some();
+
+        let mut storage = serde_json::Map::new();
+        let mut storage_ok = true;
+        for (name, result) in storage_checks(config) {
+            storage_ok &= result.is_ok();
+            let status = result.map(|()| "ok".to_string()).unwrap_or_else(|e| e.to_string());
+            storage.insert(name, serde_json::Value::String(status));
+        }
+
+        let ready = store_loaded && pending.is_empty() && storage_ok;
+        let body = serde_json::json!({
+            "ready": ready,
+            "store": { "loaded": store_loaded, "applets": applets.len() },
+            "pinned": { "total": pinned.len(), "pending": pending },
+            "storage": storage,
+        });
+        let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        warp::reply::with_status(warp::reply::json(&body), status).into_response()
+    })
+}
+
+/// Every place substrate writes to, and whether it can be written
+fn storage_checks(config: &config::Config) -> Vec<(String, std::io::Result<()>)> {
+    let mut checks = Vec::new();
+    for sink in &config.log_sinks {
+        if let SinkKind::File(path) = sink {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            checks.push((format!("log file {}", path.display()), writable_dir(dir)));
+        }
+    }
+    if let Some(dir) = &config.record_dir {
+        checks.push((format!("recordings {}", dir.display()), writable_dir(dir)));
+    }
+    checks
+}
+
+/// Check that `dir` is a directory this process may write to, without
+/// creating or writing anything
+fn writable_dir(dir: &Path) -> std::io::Result<()> {
+    let metadata = std::fs::metadata(dir)?;
+    if !metadata.is_dir() || !may_write(dir, &metadata) {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "not a writable directory"));
+    }
+    Ok(())
+}
+
+/// Ask the kernel, so ownership, groups and read-only mounts are accounted for
+#[cfg(unix)]
+fn may_write(dir: &Path, _metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a NUL-terminated string that outlives the call
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn may_write(_dir: &Path, metadata: &std::fs::Metadata) -> bool {
+    !metadata.permissions().readonly()
+}
+
+/// `GET /version`: build and engine information
+fn version() -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("version").and(warp::get()).map(|| {
+        let config = config::global_config();
+        let mut features: Vec<&str> = executor::ENGINE_FEATURES.to_vec();
+        for (enabled, feature) in [
+            (config.admin_enabled, "admin"),
+            (config.otlp_endpoint.is_some(), "otlp"),
+            (config.record_dir.is_some(), "recording"),
+            (config.watch_dir.is_some(), "watch"),
+        ] {
+            if enabled {
+                features.push(feature);
+            }
+        }
+        warp::reply::json(&serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "wasmtime": env!("WASMTIME_VERSION"),
+            "features": features,
+            "host_functions": host::HOST_FUNCTIONS,
+            "engine_fingerprint": executor::engine_fingerprint().unwrap_or_else(|e| format!("unavailable: {}", e)),
+        }))
+        .into_response()
+    })
+}
+
+/// Convert an applet's HttpResponse into a warp response
+fn into_reply(response: HttpResponse) -> warp::reply::Response {
+    let mut reply = warp::reply::Response::new(response.body.into());
+    *reply.status_mut() = StatusCode::from_u16(response.status_code)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    *reply.headers_mut() = response.headers;
+    reply
+}
+
+/// Build the response for a guest that streams its own: the body is sent
+/// as its frames arrive, followed by the trailers on HTTP/2 connections
+fn streamed_reply(head: StreamedHead, request_id: &str) -> warp::reply::Response {
+    let StreamedHead { status_code, headers, mut frames } = head;
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            let sent = match frame {
+                ResponseFrame::Data(chunk) => sender.send_data(chunk).await.is_ok(),
+                ResponseFrame::Trailers(trailers) => sender.send_trailers(trailers).await.is_ok(),
+                ResponseFrame::Abort => return sender.abort(),
+            };
+            // Dropping the frames tells the guest the client went away
+            if !sent {
+                return;
+            }
+        }
+    });
+
+    let mut reply = into_reply(HttpResponse { status_code, headers, body: Vec::new() });
+    *reply.body_mut() = body;
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    reply
+}
+
+/// The applet's CORS policy: the configured one with its overrides applied
+fn cors_policy(store: &AppletStore, uuid: Uuid) -> CorsPolicy {
+    let overrides = store.metadata(&uuid).map(|metadata| metadata.settings.cors).unwrap_or_default();
+    config::global_config().cors.with(&overrides)
+}
+
+/// Finish a preflight answered without the guest: echo the request ID and
+/// count and log it like any other request
+fn preflight_reply(mut reply: warp::reply::Response, access: AccessEntry, elapsed: Duration) -> warp::reply::Response {
+    if let Ok(value) = HeaderValue::from_str(&access.request_id) {
+        reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    metrics::record_request(access.applet, reply.status().as_u16(), elapsed);
+    access.log(&reply, elapsed);
+    reply
+}
+
+/// Render a JSON 404 for a path that names no applet
+fn not_found_reply(applet_id: &str) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": format!("Applet not found: {}", applet_id) })),
+        StatusCode::NOT_FOUND,
+    )
+    .into_response()
+}
+
+/// Render a throttled request as a JSON 429 (client over its rate) or 503
+/// (applet at its concurrency cap) response with `Retry-After`
+fn throttled_reply(throttled: &throttle::Throttled) -> warp::reply::Response {
+    let (status, message, retry_after) = match throttled {
+        throttle::Throttled::RateLimited(wait) => {
+            (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded", wait.as_secs_f64().ceil().max(1.0) as u64)
+        }
+        throttle::Throttled::Busy => (StatusCode::SERVICE_UNAVAILABLE, "Applet is at its concurrency limit", 1),
+    };
+    let mut reply =
+        warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status).into_response();
+    reply.headers_mut().insert("retry-after", HeaderValue::from(retry_after));
+    reply
+}
+
+/// Render a refused request as a JSON 413 (over a size limit) or 400
+/// (body could not be read) response
+fn refused_reply(refused: &Refused) -> warp::reply::Response {
+    let (status, message) = match refused {
+        Refused::HeadersTooLarge(limit) => {
+            (StatusCode::PAYLOAD_TOO_LARGE, format!("Request headers exceed the {} byte limit", limit))
+        }
+        Refused::InvalidSeed(message) => (StatusCode::BAD_REQUEST, message.clone()),
+        Refused::Body(e @ BodyError::TooLarge(_)) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+        Refused::Body(e @ BodyError::Failed(_)) => (StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status).into_response()
+}
+
+/// Render a failed authentication as a JSON 401 response
+fn unauthorized_reply(policy: AuthPolicy, err: &anyhow::Error) -> warp::reply::Response {
+    let mut reply = warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": err.to_string() })),
+        StatusCode::UNAUTHORIZED,
+    )
+    .into_response();
+    if policy == AuthPolicy::Jwt {
+        reply.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer"));
+    }
+    reply
+}
+
+/// Render a runner error as a JSON 500 response
+fn error_reply(err: &anyhow::Error) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": err.to_string() })),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
+
+/// Use the caller's `X-Request-Id` when it is sane, otherwise generate one
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// What the access log needs to know about a request
+struct AccessEntry {
+    method: String,
+    path: String,
+    applet: Uuid,
+    request_id: String,
+    remote_addr: Option<std::net::SocketAddr>,
+}
+
+impl AccessEntry {
+    /// Emit one access log line under the `access` topic, using the
+    /// configured format
+    fn log(&self, reply: &warp::reply::Response, elapsed: Duration) {
+        let remote = self
+            .remote_addr
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let bytes = reply.body().size_hint().exact().unwrap_or(0);
+
+        let line = render(&config::global_config().access_log_format, |name| {
+            Some(match name {
+                "remote" => remote.clone(),
+                "method" => self.method.clone(),
+                "path" => self.path.clone(),
+                "applet" => self.applet.to_string(),
+                "status" => reply.status().as_str().to_string(),
+                "bytes" => bytes.to_string(),
+                "duration_ms" => format!("{:.3}", elapsed.as_secs_f64() * 1000.0),
+                "request_id" => self.request_id.clone(),
+                _ => return None,
+            })
+        });
+        log::log("access", &line);
+    }
+}
+
+/// Fill the `{name}` placeholders of a template in one pass, so values
+/// taken from the request are never read as placeholders themselves.
+/// Unknown placeholders are kept as they are.
+fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut line = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        line.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let substituted = rest.find('}').and_then(|end| Some((value(&rest[1..end])?, end)));
+        match substituted {
+            Some((text, end)) => {
+                line.push_str(&text);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                line.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    line.push_str(rest);
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_body_bytes: usize) -> Limits {
+        Limits { max_body_bytes, ..Default::default() }
+    }
+
+    fn body(chunks: &[&'static str]) -> impl futures_util::Stream<Item = Result<Bytes, warp::Error>> + Send + Unpin {
+        futures_util::stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))).collect::<Vec<_>>())
+    }
+
+    fn too_large(received: Result<(Bytes, Option<BodyStream>), Refused>) -> Option<usize> {
+        match received {
+            Err(Refused::Body(BodyError::TooLarge(limit))) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_are_buffered_up_to_the_limit() {
+        let Ok((buffered, None)) = receive_body(&HeaderMap::new(), &limits(6), false, body(&["abc", "def"])).await else {
+            panic!("expected a buffered body");
+        };
+        assert_eq!(buffered, "abcdef");
+        assert_eq!(too_large(receive_body(&HeaderMap::new(), &limits(5), false, body(&["abc", "def"])).await), Some(5));
+
+        // 0 means unlimited
+        assert!(receive_body(&HeaderMap::new(), &limits(0), false, body(&["abc", "def"])).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn declared_lengths_over_the_limit_are_refused_before_reading() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", HeaderValue::from_static("100"));
+        assert_eq!(too_large(receive_body(&headers, &limits(10), false, body(&[])).await), Some(10));
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_end_with_an_error_past_the_limit() {
+        let Ok((buffered, Some(stream))) = receive_body(&HeaderMap::new(), &limits(4), true, body(&["abc", "def"])).await
+        else {
+            panic!("expected a streamed body");
+        };
+        assert!(buffered.is_empty());
+        let chunks = tokio::task::spawn_blocking(move || (stream.next_chunk(), stream.next_chunk())).await.unwrap();
+        assert_eq!(chunks.0.unwrap().unwrap(), "abc");
+        assert!(matches!(chunks.1, Err(BodyError::TooLarge(4))));
+    }
+
+    fn streamed_head(frames: Vec<ResponseFrame>) -> StreamedHead {
+        let (sender, receiver) = tokio::sync::mpsc::channel(frames.len().max(1));
+        for frame in frames {
+            sender.try_send(frame).ok().unwrap();
+        }
+        StreamedHead { status_code: 202, headers: HeaderMap::new(), frames: receiver }
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_carry_the_frames_then_the_trailers() {
+        use warp::hyper::body::HttpBody;
+
+        let trailers = HeaderMap::from_iter([(warp::http::header::HeaderName::from_static("grpc-status"), 0.into())]);
+        let head = streamed_head(vec![
+            ResponseFrame::Data(Bytes::from("ab")),
+            ResponseFrame::Data(Bytes::from("cd")),
+            ResponseFrame::Trailers(trailers),
+        ]);
+        let mut reply = streamed_reply(head, "req-1");
+        assert_eq!(reply.status(), StatusCode::ACCEPTED);
+        assert_eq!(reply.headers()[REQUEST_ID_HEADER], "req-1");
+        assert_eq!(reply.body_mut().data().await.unwrap().unwrap(), "ab");
+        assert_eq!(reply.body_mut().data().await.unwrap().unwrap(), "cd");
+        assert!(reply.body_mut().data().await.is_none());
+        assert_eq!(reply.body_mut().trailers().await.unwrap().unwrap()["grpc-status"], "0");
+    }
+
+    #[tokio::test]
+    async fn aborted_streamed_replies_end_in_an_error() {
+        let head = streamed_head(vec![ResponseFrame::Data(Bytes::from("ab")), ResponseFrame::Abort]);
+        assert!(warp::hyper::body::to_bytes(streamed_reply(head, "req-1").into_body()).await.is_err());
+    }
+
+    #[test]
+    fn storage_checks_leave_the_filesystem_alone() {
+        let dir = std::env::temp_dir().join(format!("substrate-storage-{}", Uuid::new_v4()));
+        assert!(writable_dir(&dir).is_err());
+        assert!(!dir.exists());
+        std::fs::create_dir(&dir).unwrap();
+        writable_dir(&dir).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    fn stub(path: &'static str) -> Routes {
+        warp::path(path).map(move || path.into_response()).boxed()
+    }
+
+    async fn status(routes: &Routes, path: &str) -> StatusCode {
+        warp::test::request().path(path).reply(routes).await.status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_can_get_their_own_listener() {
+        let (public, admin) = split_routes(stub("healthz"), stub("_admin"), stub("app"), true);
+        let admin = admin.unwrap();
+        assert_eq!(status(&public, "/healthz").await, StatusCode::OK);
+        assert_eq!(status(&public, "/app").await, StatusCode::OK);
+        assert_eq!(status(&public, "/_admin").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&admin, "/healthz").await, StatusCode::OK);
+        assert_eq!(status(&admin, "/_admin").await, StatusCode::OK);
+        assert_eq!(status(&admin, "/app").await, StatusCode::NOT_FOUND);
+
+        let (shared, admin) = split_routes(stub("healthz"), stub("_admin"), stub("app"), false);
+        assert!(admin.is_none());
+        for path in ["/healthz", "/_admin", "/app"] {
+            assert_eq!(status(&shared, path).await, StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn refusals_map_to_statuses() {
+        let status = |refused| refused_reply(&refused).status();
+        assert_eq!(status(Refused::Body(BodyError::TooLarge(10))), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(status(Refused::HeadersTooLarge(10)), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(status(Refused::Body(BodyError::Failed("reset".to_string()))), StatusCode::BAD_REQUEST);
+        assert_eq!(status(Refused::InvalidSeed("bad".to_string())), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn access_lines_do_not_expand_request_values() {
+        let values = |name: &str| {
+            Some(match name {
+                "path" => "/app/{status}?id={request_id}".to_string(),
+                "status" => "200".to_string(),
+                "request_id" => "{bytes}".to_string(),
+                _ => return None,
+            })
+        };
+        assert_eq!(
+            render("\"{path}\" {status} {unknown} {request_id} {", values),
+            "\"/app/{status}?id={request_id}\" 200 {unknown} {bytes} {"
+        );
+    }
 }
@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, Method};
+
+use crate::applet_store::AppletStore;
+use crate::auth::{self, Principal};
+use crate::types::{HttpRequest, HttpResponse};
+use crate::config::RateLimitKey;
+use crate::{config, log};
+
+/// Recorded in place of a credential
+const REDACTED: &str = "<redacted>";
+
+/// The result a host function returned to the guest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostCall {
+    pub function: String,       // Host function that was called
+    pub result: Option<String>, // What it returned, if anything
+}
+
+/// One recorded invocation, stored as a line of `<dir>/<applet>.jsonl`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Recording {
+    pub recorded_at: String,       // RFC 3339 time the request was served
+    pub applet: String,            // Name of the applet that served it
+    pub request: RecordedRequest,  // The request as the runner saw it
+    pub host_calls: Vec<HostCall>, // Host call results, in call order
+    pub outcome: Outcome,          // What the runner returned
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String, // Base64
+    pub remote_addr: Option<String>,
+    pub request_id: String,
+    #[serde(default)]
+    pub principal: Option<Principal>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String, // Base64
+}
+
+/// A response, or the error the runner failed with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Response(RecordedResponse),
+    Error(String),
+}
+
+impl RecordedRequest {
+    /// Capture the request with its credentials redacted, including the
+    /// cookies (which the recording only keeps as a header) and the header
+    /// named by a `header:<name>` rate limit key
+    pub fn new(request: &HttpRequest, key_header: Option<&str>) -> Self {
+        RecordedRequest {
+            method: request.method.to_string(),
+            path: request.path.clone(),
+            query: request.query.clone(),
+            headers: redacted_pairs(&request.headers, key_header),
+            body: BASE64.encode(&request.body),
+            remote_addr: request.remote_addr.map(|addr| addr.to_string()),
+            request_id: request.request_id.clone(),
+            principal: request.principal.clone(),
+        }
+    }
+
+    /// Rebuild the request for the runner
+    pub fn to_request(&self) -> Result<HttpRequest> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.append(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        Ok(HttpRequest {
+            method: Method::from_bytes(self.method.as_bytes())?,
+            cookies: headers.get("cookie").and_then(|value| value.to_str().ok()).map(String::from),
+            headers,
+            path: self.path.clone(),
+            query: self.query.clone(),
+            body: Bytes::from(BASE64.decode(&self.body)?),
+            body_stream: None,
+            remote_addr: self.remote_addr.as_deref().and_then(|addr| addr.parse().ok()),
+            request_id: self.request_id.clone(),
+            principal: self.principal.clone(),
+            response_sink: None,
+        })
+    }
+}
+
+impl Outcome {
+    pub fn new(result: &Result<HttpResponse>) -> Self {
+        match result {
+            Ok(response) => Outcome::Response(RecordedResponse {
+                status: response.status_code,
+                headers: redacted_pairs(&response.headers, None),
+                body: BASE64.encode(&response.body),
+            }),
+            Err(e) => Outcome::Error(format!("{:#}", e)),
+        }
+    }
+
+    /// The outcome without its redacted headers, whose values a replay
+    /// cannot be compared against
+    pub fn without_redacted(&self) -> Outcome {
+        match self {
+            Outcome::Response(response) => Outcome::Response(RecordedResponse {
+                headers: response.headers.iter().filter(|(_, value)| value != REDACTED).cloned().collect(),
+                ..response.clone()
+            }),
+            Outcome::Error(message) => Outcome::Error(message.clone()),
+        }
+    }
+}
+
+fn redacted_pairs(headers: &HeaderMap, key_header: Option<&str>) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match auth::CREDENTIAL_HEADERS.contains(&name.as_str()) || key_header == Some(name.as_str()) {
+                true => REDACTED.to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Decode a base64 body for display
+pub fn decode_body(body: &str) -> Vec<u8> {
+    BASE64.decode(body).unwrap_or_default()
+}
+
+/// Encode bytes a host call returns so they can be put on the tape
+pub fn encode_body(body: &[u8]) -> String {
+    BASE64.encode(body)
+}
+
+/// Host call results of the invocation running on this thread
+enum Tape {
+    Recording(Vec<HostCall>),
+    Replaying(VecDeque<HostCall>),
+}
+
+thread_local! {
+    static TAPE: RefCell<Option<Tape>> = const { RefCell::new(None) };
+}
+
+/// Clears this thread's tape when dropped
+pub struct TapeGuard;
+
+impl TapeGuard {
+    /// Stop recording and return the host calls seen so far
+    pub fn finish(self) -> Vec<HostCall> {
+        TAPE.with(|tape| match tape.borrow_mut().take() {
+            Some(Tape::Recording(calls)) => calls,
+            _ => Vec::new(),
+        })
+    }
+}
+
+impl Drop for TapeGuard {
+    fn drop(&mut self) {
+        TAPE.with(|tape| tape.borrow_mut().take());
+    }
+}
+
+/// Capture host call results made on this thread until the guard is finished
+pub fn start_recording() -> TapeGuard {
+    TAPE.with(|tape| *tape.borrow_mut() = Some(Tape::Recording(Vec::new())));
+    TapeGuard
+}
+
+/// Whether host calls made on this thread are being recorded
+pub fn is_recording() -> bool {
+    TAPE.with(|tape| matches!(tape.borrow().as_ref(), Some(Tape::Recording(_))))
+}
+
+/// Answer host calls made on this thread from `calls` instead of live values
+pub fn start_replay(calls: Vec<HostCall>) -> TapeGuard {
+    TAPE.with(|tape| *tape.borrow_mut() = Some(Tape::Replaying(calls.into())));
+    TapeGuard
+}
+
+/// Route a host call result through the tape: recorded while recording,
+/// taken from the recording while replaying, and computed live otherwise
+pub fn host_call(function: &str, live: impl FnOnce() -> Option<String>) -> Result<Option<String>> {
+    TAPE.with(|tape| match tape.borrow_mut().as_mut() {
+        None => Ok(live()),
+        Some(Tape::Recording(calls)) => {
+            let result = live();
+            calls.push(HostCall { function: function.to_string(), result: result.clone() });
+            Ok(result)
+        }
+        Some(Tape::Replaying(calls)) => match calls.pop_front() {
+            Some(call) if call.function == function => Ok(call.result),
+            Some(call) => Err(anyhow!(
+                "Replay diverged: guest called `{}` where the recording has `{}`",
+                function,
+                call.function
+            )),
+            None => Err(anyhow!("Replay diverged: guest called `{}` more often than recorded", function)),
+        },
+    })
+}
+
+/// An invocation being recorded; created before the runner is called
+pub struct Recorder {
+    applet: String,
+    request: RecordedRequest,
+    tape: TapeGuard,
+}
+
+impl Recorder {
+    /// Start recording the invocation when recording is enabled for the applet
+    pub fn start(store: &AppletStore, uuid: Uuid, request: &HttpRequest) -> Option<Recorder> {
+        let config = config::global_config();
+        config.record_dir.as_ref()?;
+        let metadata = store.metadata(&uuid)?;
+        if !config.record_applets.is_empty() && !config.record_applets.contains(&metadata.name) {
+            return None;
+        }
+        let limits = config.limits_for(&metadata.name).with(&metadata.settings.limits);
+        let key_header = match &limits.rate_limit_key {
+            RateLimitKey::Header(name) => Some(name.as_str()),
+            _ => None,
+        };
+        Some(Recorder {
+            request: RecordedRequest::new(request, key_header),
+            applet: metadata.name,
+            tape: start_recording(),
+        })
+    }
+
+    /// Append the invocation and its outcome to the applet's recording file
+    pub fn finish(self, result: &Result<HttpResponse>) {
+        let recording = Recording {
+            recorded_at: chrono::Local::now().to_rfc3339(),
+            applet: self.applet,
+            request: self.request,
+            host_calls: self.tape.finish(),
+            outcome: Outcome::new(result),
+        };
+        if let Err(e) = write(&recording) {
+            log::log_with(
+                "substrate",
+                log::Level::Error,
+                &format!("Failed to write recording for '{}': {}", recording.applet, e),
+                &[],
+            );
+        }
+    }
+}
+
+/// Read every invocation in a recording file
+pub fn read(path: &Path) -> Result<Vec<Recording>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str::<Recording>(line).with_context(|| format!("Invalid recording on line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Serializes appends to the recording files
+static WRITER: Mutex<()> = Mutex::new(());
+
+fn write(recording: &Recording) -> Result<()> {
+    let Some(dir) = &config::global_config().record_dir else {
+        return Ok(());
+    };
+    let line = serde_json::to_string(recording)?;
+
+    let _lock = WRITER.lock().unwrap();
+    std::fs::create_dir_all(dir)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("{}.jsonl", recording.applet)))?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_answers_from_the_recorded_tape() {
+        let tape = start_recording();
+        assert!(is_recording());
+        assert_eq!(host_call("random", || Some("4".to_string())).unwrap().as_deref(), Some("4"));
+        assert_eq!(host_call("log", || None).unwrap(), None);
+        let calls = tape.finish();
+        assert!(!is_recording());
+        assert_eq!(calls.len(), 2);
+
+        let _tape = start_replay(calls);
+        assert!(!is_recording());
+        let live = || panic!("replay must not compute live values");
+        assert_eq!(host_call("random", live).unwrap().as_deref(), Some("4"));
+        assert_eq!(host_call("log", live).unwrap(), None);
+        assert!(host_call("log", live).is_err());
+    }
+
+    #[test]
+    fn replay_fails_when_the_guest_diverges() {
+        let calls = vec![HostCall { function: "random".to_string(), result: Some("4".to_string()) }];
+        let _tape = start_replay(calls);
+        let error = host_call("time", || None).unwrap_err();
+        assert!(error.to_string().contains("`time` where the recording has `random`"));
+    }
+
+    #[test]
+    fn credentials_are_redacted() {
+        let request = RecordedRequest {
+            method: "GET".to_string(),
+            path: "/echo".to_string(),
+            query: String::new(),
+            headers: vec![
+                ("cookie".to_string(), "session=abc".to_string()),
+                ("authorization".to_string(), "Bearer abc".to_string()),
+                ("x-client".to_string(), "abc".to_string()),
+                ("accept".to_string(), "*/*".to_string()),
+            ],
+            body: String::new(),
+            remote_addr: None,
+            request_id: "1".to_string(),
+            principal: None,
+        }
+        .to_request()
+        .unwrap();
+
+        let recorded = RecordedRequest::new(&request, Some("x-client"));
+        let value = |name: &str| recorded.headers.iter().find(|(header, _)| header == name).unwrap().1.clone();
+        assert_eq!(value("cookie"), "<redacted>");
+        assert_eq!(value("authorization"), "<redacted>");
+        assert_eq!(value("x-client"), "<redacted>");
+        assert_eq!(value("accept"), "*/*");
+        assert_eq!(recorded.to_request().unwrap().cookies.as_deref(), Some("<redacted>"));
+    }
+
+    #[test]
+    fn replays_do_not_compare_redacted_headers() {
+        let response = |session: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("set-cookie", HeaderValue::from_str(session).unwrap());
+            headers.insert("content-type", HeaderValue::from_static("text/plain"));
+            Outcome::new(&Ok(HttpResponse { status_code: 200, headers, body: b"ok".to_vec() }))
+        };
+        let recorded = response("session=abc");
+        let Outcome::Response(recorded_response) = &recorded else {
+            panic!("expected a response");
+        };
+        assert!(recorded_response.headers.contains(&("set-cookie".to_string(), "<redacted>".to_string())));
+
+        let Outcome::Response(compared) = recorded.without_redacted() else {
+            panic!("expected a response");
+        };
+        assert_eq!(compared.headers, [("content-type".to_string(), "text/plain".to_string())]);
+        assert_eq!(recorded.without_redacted(), response("session=def").without_redacted());
+    }
+
+    #[test]
+    fn without_a_tape_calls_are_live() {
+        assert_eq!(host_call("random", || Some("7".to_string())).unwrap().as_deref(), Some("7"));
+    }
+}
@@ -1,11 +1,20 @@
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::time::Instant;
 use uuid::Uuid;
 use anyhow::{Result, anyhow};
+use bytes::Bytes;
 use serde_json::Value;
-use crate::applet_store::AppletStore;
+use tokio::sync::mpsc;
+use wasmtime::Val;
+use crate::applet_store::{AppletSettings, AppletStore};
+use crate::config::Limits;
 use crate::types::{HttpRequest, HttpResponse};
-use crate::executor::Executor;
+use crate::executor::{ExecutionStats, Executor, Session};
+use crate::log::{self, LogContext};
+use crate::{config, determinism, metrics, trace};
+use warp::http::HeaderValue;
+use warp::ws::Message;
 
 /// Runner for executing WebAssembly applets with caching
 pub struct Runner {
@@ -24,38 +33,165 @@ impl Runner {
 
     /// Executes the applet identified by UUID with the given request
     pub fn run(&self, uuid: Uuid, request: HttpRequest) -> Result<HttpResponse> {
+        self.run_with_stats(uuid, request).map(|(response, _)| response)
+    }
+
+    /// Like `run`, also returning the resources the invocation used
+    pub fn run_with_stats(&self, uuid: Uuid, request: HttpRequest) -> Result<(HttpResponse, ExecutionStats)> {
+        // Tag every log line emitted during this invocation
+        let _context = log::enter(LogContext {
+            applet: uuid,
+            request_id: request.request_id.clone(),
+            started_at: Instant::now(),
+        });
+
         // Get or cache the executor
-        let executor = self.get_or_cache_executor(uuid)?;
+        let executor = {
+            let _span = trace::start("cache.lookup");
+            self.get_or_cache_executor(uuid)?
+        };
+        let limits = self.limits_for(uuid);
+        let seed = self.seed_for(uuid, &request)?;
 
         // Prepare arguments for execution
         let args = self.prepare_args(&request)?;
 
         // Execute the module and collect the result
-        let result = executor.execute(request.body.as_ref(), &args)?;
+        let (result, stats, streamed) = executor.execute(&args, limits, seed, &request)?;
+        metrics::record_execution(uuid, stats.fuel_consumed, stats.memory_bytes);
 
-        // Convert the result into an HttpResponse
-        self.prepare_response(result)
+        // Convert the result into an HttpResponse, unless the guest wrote
+        // one itself, reporting the seed of a deterministic run so it can be
+        // reproduced
+        let mut response = match streamed {
+            Some(response) => response,
+            None => self.prepare_response(result)?,
+        };
+        if let Some(seed) = seed {
+            response.headers.insert(determinism::SEED_HEADER, HeaderValue::from(seed));
+        }
+        Ok((response, stats))
     }
 
-    /// Gets the cached executor or caches a new one if not already stored
+    /// Instantiate the applet for the WebSocket connection `request` opened;
+    /// frames the guest sends go to `frames`
+    pub fn open_socket(&self, uuid: Uuid, request: HttpRequest, frames: mpsc::Sender<Message>) -> Result<SocketSession> {
+        let context = LogContext {
+            applet: uuid,
+            request_id: request.request_id.clone(),
+            started_at: Instant::now(),
+        };
+        let _context = log::enter(context.clone());
+
+        let executor = self.get_or_cache_executor(uuid)?;
+        let limits = self.limits_for(uuid);
+        let seed = self.seed_for(uuid, &request)?;
+        let session = executor.open_session(limits, seed, &request, frames)?;
+        Ok(SocketSession { uuid, context, session })
+    }
+
+    /// Seed for a deterministic run: the one the request asks for, when the
+    /// applet lets requests pick it, or a new one when the applet always runs
+    /// deterministically. Otherwise the header is ignored, so clients cannot
+    /// make an applet's random output predictable.
+    fn seed_for(&self, uuid: Uuid, request: &HttpRequest) -> Result<Option<u64>> {
+        let settings = self.store.metadata(&uuid).map(|metadata| metadata.settings).unwrap_or_default();
+        if settings.allow_seed_header {
+            if let Some(seed) = determinism::requested_seed(&request.headers)? {
+                return Ok(Some(seed));
+            }
+        }
+        Ok(settings.deterministic.then(determinism::new_seed))
+    }
+
+    /// Compile the applet ahead of its first request
+    pub fn warm(&self, uuid: Uuid) -> Result<()> {
+        self.get_or_cache_executor(uuid).map(|_| ())
+    }
+
+    /// Whether the applet has a compiled executor ready
+    pub fn is_compiled(&self, uuid: &Uuid) -> bool {
+        self.cache.lock().unwrap().contains_key(uuid)
+    }
+
+    /// Gets the cached executor or caches a new one if not already stored.
+    /// Compiling happens outside the cache lock, so lookups for other
+    /// applets (and readiness checks) are not held up by it; when two
+    /// requests compile the same applet, the first executor cached wins.
     fn get_or_cache_executor(&self, uuid: Uuid) -> Result<Arc<Executor>> {
-        let mut cache = self.cache.lock().unwrap();
-        if let Some(executor) = cache.get(&uuid) {
+        if let Some(executor) = self.cache.lock().unwrap().get(&uuid) {
+            metrics::record_cache_lookup(true);
             return Ok(Arc::clone(executor)); // Return cached executor
         }
+        metrics::record_cache_lookup(false);
 
         // Fetch the Wasm binary from the applet store
-        let wasm_binary = self
+        let (wasm_binary, metadata) = self
             .store
-            .get(uuid)
+            .get(&uuid)
             .ok_or_else(|| anyhow!("Applet not found for UUID: {}", uuid))?;
 
         // Create a new Executor instance
-        let executor = Arc::new(Executor::new()?);
+        let compile_started = Instant::now();
+        let executor = {
+            let _span = trace::start("compile");
+            Arc::new(Executor::new(&wasm_binary, &metadata.settings)?)
+        };
+        metrics::record_compile(uuid, compile_started.elapsed());
 
-        // Cache the executor
-        cache.insert(uuid, Arc::clone(&executor));
-        Ok(executor)
+        // Cache the executor, unless a reload or another request got there first
+        let mut cache = self.cache.lock().unwrap();
+        Ok(Arc::clone(cache.entry(uuid).or_insert(executor)))
+    }
+
+    /// Compile an applet and add it to the store, so only applets that
+    /// compile are ever stored
+    pub fn install(&self, wasm_binary: Vec<u8>, name: String, settings: AppletSettings) -> Result<Uuid> {
+        let compile_started = Instant::now();
+        let executor = Arc::new(Executor::new(&wasm_binary, &settings)?);
+        let compile_time = compile_started.elapsed();
+
+        let mut cache = self.cache.lock().unwrap();
+        let uuid = self.store.create_with(wasm_binary, name, settings)?;
+        metrics::record_compile(uuid, compile_time);
+        cache.insert(uuid, executor);
+        Ok(uuid)
+    }
+
+    /// Compile a new version of an applet and swap it in. The store and the
+    /// cache are updated together, so requests see either the old version or
+    /// the new one; on a compile error the old version keeps serving.
+    pub fn reload(&self, uuid: Uuid, wasm_binary: Vec<u8>) -> Result<()> {
+        let metadata = self
+            .store
+            .metadata(&uuid)
+            .ok_or_else(|| anyhow!("Applet not found for UUID: {}", uuid))?;
+
+        let compile_started = Instant::now();
+        let executor = Arc::new(Executor::new(&wasm_binary, &metadata.settings)?);
+        metrics::record_compile(uuid, compile_started.elapsed());
+
+        let mut cache = self.cache.lock().unwrap();
+        if !self.store.replace(&uuid, wasm_binary) {
+            return Err(anyhow!("Applet not found for UUID: {}", uuid));
+        }
+        cache.insert(uuid, executor);
+        Ok(())
+    }
+
+    /// Drop the cached executor of an applet, e.g. after it was deleted
+    pub fn evict(&self, uuid: &Uuid) {
+        self.cache.lock().unwrap().remove(uuid);
+    }
+
+    /// Resource limits for the applet: configured defaults, then the
+    /// configuration file's per-applet section, then its deployment settings
+    pub fn limits_for(&self, uuid: Uuid) -> Limits {
+        let config = config::global_config();
+        let Some(metadata) = self.store.metadata(&uuid) else {
+            return config.limits.clone();
+        };
+        config.limits_for(&metadata.name).with(&metadata.settings.limits)
     }
 
     /// Prepares arguments for the Wasm module execution
@@ -73,3 +209,80 @@ impl Runner {
         })
     }
 }
+
+/// An applet serving a WebSocket connection. Calls block while the guest
+/// runs, so they belong on the blocking pool.
+pub struct SocketSession {
+    uuid: Uuid,
+    context: LogContext, // Tags every line logged during the connection
+    session: Session,
+}
+
+impl SocketSession {
+    pub fn on_open(&mut self) -> Result<()> {
+        self.call(|session| session.on_open())
+    }
+
+    pub fn on_message(&mut self, message: Bytes, text: bool) -> Result<()> {
+        self.call(|session| session.on_message(message, text))
+    }
+
+    pub fn on_close(&mut self, code: u16) -> Result<()> {
+        self.call(|session| session.on_close(code))
+    }
+
+    fn call(&mut self, callback: impl FnOnce(&mut Session) -> Result<ExecutionStats>) -> Result<()> {
+        let _context = log::enter(self.context.clone());
+        let stats = callback(&mut self.session)?;
+        metrics::record_execution(self.uuid, stats.fuel_consumed, stats.memory_bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::{HeaderMap, Method};
+
+    fn request(seed: &str) -> HttpRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert(determinism::SEED_HEADER, HeaderValue::from_str(seed).unwrap());
+        HttpRequest {
+            method: Method::GET,
+            headers,
+            cookies: None,
+            path: "/".to_string(),
+            query: String::new(),
+            body: Bytes::new(),
+            body_stream: None,
+            remote_addr: None,
+            request_id: "test".to_string(),
+            principal: None,
+            response_sink: None,
+        }
+    }
+
+    fn runner_with(settings: AppletSettings) -> (Runner, Uuid) {
+        let store = Arc::new(AppletStore::new());
+        let uuid = store.create_with(Vec::new(), "seeded".to_string(), settings).unwrap();
+        (Runner::new(store).unwrap(), uuid)
+    }
+
+    #[test]
+    fn seed_header_is_ignored_unless_the_applet_allows_it() {
+        let (runner, uuid) = runner_with(AppletSettings::default());
+        assert_eq!(runner.seed_for(uuid, &request("42")).unwrap(), None);
+        assert_eq!(runner.seed_for(uuid, &request("not a seed")).unwrap(), None);
+
+        // Deterministic applets still pick a seed of their own
+        let (runner, uuid) = runner_with(AppletSettings { deterministic: true, ..Default::default() });
+        assert!(runner.seed_for(uuid, &request("42")).unwrap().is_some_and(|seed| seed != 42));
+    }
+
+    #[test]
+    fn seed_header_is_honoured_when_the_applet_allows_it() {
+        let (runner, uuid) = runner_with(AppletSettings { allow_seed_header: true, ..Default::default() });
+        assert_eq!(runner.seed_for(uuid, &request("42")).unwrap(), Some(42));
+        assert!(runner.seed_for(uuid, &request("not a seed")).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+use warp::http::HeaderMap;
+
+use crate::config::{self, Limits, RateLimitKey};
+
+/// How often buckets of clients that went quiet are dropped; however fast
+/// clients churn keys, the buckets are scanned at most once per interval
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A token bucket: holds up to `capacity` tokens, refilled at `rate` per second
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// Rate limit buckets by applet and client key
+struct Buckets {
+    by_client: HashMap<(Uuid, String), Bucket>,
+    swept: Instant, // When idle buckets were last dropped
+}
+
+impl Buckets {
+    fn new(now: Instant) -> Self {
+        Buckets { by_client: HashMap::new(), swept: now }
+    }
+
+    /// Take a token from the client's bucket, creating a full one for a new client
+    fn take(&mut self, applet: Uuid, key: String, limits: &Limits, now: Instant) -> Result<(), Throttled> {
+        self.sweep(now);
+        let rate = limits.rate_limit as f64;
+        let capacity = if limits.rate_burst > 0 { limits.rate_burst } else { limits.rate_limit } as f64;
+        let bucket = self
+            .by_client
+            .entry((applet, key))
+            .or_insert(Bucket { tokens: capacity, capacity, rate, updated: now });
+
+        // Limits can change with a reload of the applet's settings
+        bucket.capacity = capacity;
+        bucket.rate = rate;
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Throttled::RateLimited(Duration::from_secs_f64((1.0 - bucket.tokens) / rate)))
+    }
+
+    /// Once per interval, drop the buckets that have refilled: a full bucket
+    /// is the same as the new one its client would get
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept = now;
+        self.by_client.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.capacity
+        });
+    }
+}
+
+static BUCKETS: OnceLock<Mutex<Buckets>> = OnceLock::new();
+
+/// Invocations running per applet
+static RUNNING: OnceLock<Mutex<HashMap<Uuid, usize>>> = OnceLock::new();
+
+fn buckets() -> &'static Mutex<Buckets> {
+    BUCKETS.get_or_init(|| Mutex::new(Buckets::new(Instant::now())))
+}
+
+fn running() -> &'static Mutex<HashMap<Uuid, usize>> {
+    RUNNING.get_or_init(Default::default)
+}
+
+/// Why a request was turned away
+pub enum Throttled {
+    RateLimited(Duration), // The client may retry after this long
+    Busy,                  // The applet is at its concurrency cap
+}
+
+/// Counts an admitted invocation against its applet's cap until dropped
+pub struct Permit {
+    applet: Option<Uuid>, // Set when the applet has a cap
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Some(applet) = self.applet else {
+            return;
+        };
+        if let Some(count) = running().lock().unwrap().get_mut(&applet) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Take a token from the client's bucket and a slot under the applet's
+/// concurrency cap, or say why the request has to wait
+pub fn admit(
+    applet: Uuid,
+    limits: &Limits,
+    headers: &HeaderMap,
+    remote_addr: Option<SocketAddr>,
+) -> Result<Permit, Throttled> {
+    if limits.rate_limit > 0 {
+        let key = client_key(&limits.rate_limit_key, headers, remote_addr);
+        take_token(applet, key, limits)?;
+    }
+
+    if limits.max_concurrency == 0 {
+        return Ok(Permit { applet: None });
+    }
+    let mut running = running().lock().unwrap();
+    let count = running.entry(applet).or_default();
+    if *count >= limits.max_concurrency {
+        return Err(Throttled::Busy);
+    }
+    *count += 1;
+    Ok(Permit { applet: Some(applet) })
+}
+
+fn take_token(applet: Uuid, key: String, limits: &Limits) -> Result<(), Throttled> {
+    buckets().lock().unwrap().take(applet, key, limits, Instant::now())
+}
+
+/// The value clients are told apart by; clients without one share a bucket.
+/// API keys count only once verified, so a client cannot get a fresh bucket
+/// by making one up: without a configured key it is limited by its IP.
+fn client_key(key: &RateLimitKey, headers: &HeaderMap, remote_addr: Option<SocketAddr>) -> String {
+    let ip = || remote_addr.map(|addr| addr.ip().to_string());
+    match key {
+        RateLimitKey::Ip => ip(),
+        RateLimitKey::ApiKey => match config::global_config().auth.api_key_name(headers) {
+            Some(name) => Some(format!("key:{}", name)),
+            None => ip(),
+        },
+        RateLimitKey::Header(name) => headers.get(name).and_then(|value| value.to_str().ok()).map(String::from),
+        RateLimitKey::Applet => None,
+    }
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rate_limit: u32, rate_burst: u32) -> Limits {
+        Limits { rate_limit, rate_burst, ..Default::default() }
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill_at_the_rate() {
+        let applet = Uuid::new_v4();
+        let limits = limits(20, 3);
+        for _ in 0..3 {
+            assert!(take_token(applet, "a".to_string(), &limits).is_ok());
+        }
+        match take_token(applet, "a".to_string(), &limits) {
+            Err(Throttled::RateLimited(retry_after)) => assert!(retry_after <= Duration::from_millis(50)),
+            _ => panic!("expected the fourth request to be rate limited"),
+        }
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(take_token(applet, "a".to_string(), &limits).is_ok());
+    }
+
+    #[test]
+    fn clients_and_applets_have_their_own_buckets() {
+        let applet = Uuid::new_v4();
+        let limits = limits(1, 0);
+        assert!(take_token(applet, "a".to_string(), &limits).is_ok());
+        assert!(take_token(applet, "a".to_string(), &limits).is_err());
+        assert!(take_token(applet, "b".to_string(), &limits).is_ok());
+        assert!(take_token(Uuid::new_v4(), "a".to_string(), &limits).is_ok());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept_once_per_interval() {
+        let start = Instant::now();
+        let mut buckets = Buckets::new(start);
+        let applet = Uuid::new_v4();
+        for key in ["a", "b"] {
+            assert!(buckets.take(applet, key.to_string(), &limits(10, 0), start).is_ok());
+        }
+
+        // Refilled, but not swept before the interval is up
+        let later = start + Duration::from_secs(1);
+        assert!(buckets.take(applet, "c".to_string(), &limits(10, 0), later).is_ok());
+        assert_eq!(buckets.by_client.len(), 3);
+
+        // A client still waiting for its token keeps its bucket
+        let waiting = start + SWEEP_INTERVAL - Duration::from_millis(500);
+        assert!(buckets.take(applet, "slow".to_string(), &limits(1, 0), waiting).is_ok());
+        let due = start + SWEEP_INTERVAL;
+        assert!(buckets.take(applet, "a".to_string(), &limits(10, 0), due).is_ok());
+        let mut kept: Vec<_> = buckets.by_client.keys().map(|(_, key)| key.as_str()).collect();
+        kept.sort();
+        assert_eq!(kept, ["a", "slow"]);
+        assert!(buckets.take(applet, "slow".to_string(), &limits(1, 0), due).is_err());
+    }
+
+    #[test]
+    fn client_keys() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        let addr = Some("10.0.0.1:4000".parse().unwrap());
+        assert_eq!(client_key(&RateLimitKey::Ip, &headers, addr), "10.0.0.1");
+        assert_eq!(client_key(&RateLimitKey::Header("x-tenant".to_string()), &headers, addr), "acme");
+        assert_eq!(client_key(&RateLimitKey::Header("x-other".to_string()), &headers, addr), "");
+        assert_eq!(client_key(&RateLimitKey::Applet, &headers, addr), "");
+    }
+}
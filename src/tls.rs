@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use tokio_rustls::rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+use crate::log::{self, Level};
+
+/// How often the certificate files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Modification time and size, used to notice that a file was rewritten
+type Signature = (SystemTime, u64);
+
+/// The certificate files being served and the configuration built from them
+struct State {
+    files: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    signatures: Mutex<Vec<Option<Signature>>>, // Of the files last loaded
+}
+
+static STATE: OnceLock<State> = OnceLock::new();
+
+/// Load the certificates and keep reloading them when the files change
+pub fn init(files: &TlsConfig) -> Result<()> {
+    let server_config = load(files)?;
+    let state = State {
+        files: files.clone(),
+        current: RwLock::new(Arc::new(server_config)),
+        signatures: Mutex::new(signatures(files)),
+    };
+    if STATE.set(state).is_err() {
+        return Err(anyhow!("TLS is already initialized"));
+    }
+
+    std::thread::Builder::new()
+        .name("tls-watch".to_string())
+        .spawn(|| loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if let Some(state) = STATE.get() {
+                if *state.signatures.lock().unwrap() != signatures(&state.files) {
+                    reload();
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Re-read the certificate files; on failure the previous certificates stay in use
+pub fn reload() {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    // Taken before reading so a change made while loading is noticed next poll
+    let seen = signatures(&state.files);
+    *state.signatures.lock().unwrap() = seen;
+    match load(&state.files) {
+        Ok(server_config) => {
+            *state.current.write().unwrap() = Arc::new(server_config);
+            log::log("substrate", "Reloaded TLS certificates");
+        }
+        Err(e) => log::log_with(
+            "substrate",
+            Level::Error,
+            &format!("Failed to reload TLS certificates, keeping the current ones: {:#}", e),
+            &[],
+        ),
+    }
+}
+
+/// Acceptor for new connections, using the most recently loaded certificates
+pub fn acceptor() -> Option<TlsAcceptor> {
+    let state = STATE.get()?;
+    let server_config = state.current.read().unwrap().clone();
+    Some(TlsAcceptor::from(server_config))
+}
+
+/// Whether admin routes require a verified client certificate
+pub fn client_cert_required() -> bool {
+    STATE.get().is_some_and(|state| state.files.client_ca.is_some())
+}
+
+/// Build a server configuration from the certificate files. With a client
+/// CA, clients may present a certificate signed by it; connections
+/// without one are still accepted and only kept out of the admin routes.
+pub fn load(files: &TlsConfig) -> Result<ServerConfig> {
+    let certs = read_certs(&files.cert)?;
+    let key = read_key(&files.key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &files.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(
+                &read_certs(path)?.into_iter().map(|cert| cert.0).collect::<Vec<_>>(),
+            );
+            if added == 0 {
+                return Err(anyhow!("No usable CA certificates in '{}'", path.display()));
+            }
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("Invalid certificate or key in '{}'", files.cert.display()))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Cannot read '{}'", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Invalid PEM in '{}'", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in '{}'", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Cannot read '{}'", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Invalid PEM in '{}'", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key in '{}'", path.display()))
+}
+
+fn signatures(files: &TlsConfig) -> Vec<Option<Signature>> {
+    [Some(&files.cert), Some(&files.key), files.client_ca.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
@@ -0,0 +1,385 @@
+use std::cell::RefCell;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{log, metrics};
+
+/// Spans sent to the collector in one OTLP request
+const BATCH_SIZE: usize = 512;
+
+/// How long finished spans may wait before the batch is flushed anyway
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Finished spans queued for export before new ones are dropped
+const EXPORT_QUEUE: usize = 8 * BATCH_SIZE;
+
+/// How long shutdown waits for the last spans to be exported
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Identifies a span within a trace, as carried by the W3C `traceparent` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parse a `traceparent` header value (`00-<trace id>-<span id>-<flags>`)
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+
+        let context = SpanContext {
+            trace_id: decode_hex(trace_id)?,
+            span_id: decode_hex(span_id)?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+        };
+
+        // All-zero identifiers are invalid per the specification
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        Some(context)
+    }
+
+    /// Render as a `traceparent` header value
+    pub fn to_traceparent(self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+/// The role a span plays, mapped onto OTLP span kinds
+#[derive(Clone, Copy, Debug)]
+pub enum SpanKind {
+    Internal,
+    Server,
+}
+
+/// Work for the export loop
+enum Export {
+    Span(FinishedSpan),
+    Flush(oneshot::Sender<()>), // Export everything queued, then acknowledge
+}
+
+/// A span that has ended and is waiting to be exported
+#[derive(Debug)]
+struct FinishedSpan {
+    name: String,
+    kind: SpanKind,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+thread_local! {
+    /// Spans currently open on this thread, innermost last
+    static STACK: RefCell<Vec<SpanContext>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An open span; it ends and is queued for export when dropped
+pub struct Span {
+    name: String,
+    kind: SpanKind,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Attach a key/value attribute to the span
+    pub fn set_attribute(&mut self, key: &str, value: impl ToString) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    /// Mark the span as failed
+    pub fn set_error(&mut self, message: impl ToString) {
+        self.error = Some(message.to_string());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(position) = stack.iter().rposition(|ctx| *ctx == self.context) {
+                stack.remove(position);
+            }
+        });
+
+        if let (Some(exporter), true) = (EXPORTER.get(), self.context.sampled) {
+            // A collector that is slow or down must not hold spans without bound
+            let span = Export::Span(FinishedSpan {
+                name: std::mem::take(&mut self.name),
+                kind: self.kind,
+                context: self.context,
+                parent_span_id: self.parent_span_id,
+                start: self.start,
+                end: SystemTime::now(),
+                attributes: std::mem::take(&mut self.attributes),
+                error: self.error.take(),
+            });
+            if exporter.try_send(span).is_err() {
+                metrics::record_dropped_span();
+            }
+        }
+    }
+}
+
+/// Start a server span for an incoming request, continuing the trace in
+/// `traceparent` when one was sent
+pub fn start_request(name: &str, traceparent: Option<&str>) -> Span {
+    let parent = traceparent.and_then(SpanContext::from_traceparent);
+    open(name, SpanKind::Server, parent)
+}
+
+/// Start a child of the innermost open span on this thread, or a new trace
+pub fn start(name: &str) -> Span {
+    open(name, SpanKind::Internal, current())
+}
+
+fn open(name: &str, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+    let ids = *Uuid::new_v4().as_bytes();
+    let mut span_id = [0u8; 8];
+    span_id.copy_from_slice(&ids[8..]);
+
+    let context = match parent {
+        Some(parent) => SpanContext { trace_id: parent.trace_id, span_id, sampled: parent.sampled },
+        None => SpanContext { trace_id: *Uuid::new_v4().as_bytes(), span_id, sampled: true },
+    };
+
+    STACK.with(|stack| stack.borrow_mut().push(context));
+
+    Span {
+        name: name.to_string(),
+        kind,
+        context,
+        parent_span_id: parent.map(|parent| parent.span_id),
+        start: SystemTime::now(),
+        attributes: Vec::new(),
+        error: None,
+    }
+}
+
+/// The innermost open span on this thread
+pub fn current() -> Option<SpanContext> {
+    STACK.with(|stack| stack.borrow().last().copied())
+}
+
+/// `traceparent` value to hand to the guest and to outbound calls
+pub fn current_traceparent() -> Option<String> {
+    current().map(|context| context.to_traceparent())
+}
+
+static EXPORTER: OnceLock<mpsc::Sender<Export>> = OnceLock::new();
+
+/// Start exporting spans to the configured OTLP/HTTP collector. Without an
+/// endpoint spans are still created (and propagated) but never exported.
+pub fn init(config: &Config) {
+    let Some(endpoint) = config.otlp_endpoint.clone() else {
+        return;
+    };
+
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let service_name = config.otlp_service_name.clone();
+    let (sender, receiver) = mpsc::channel(EXPORT_QUEUE);
+    if EXPORTER.set(sender).is_err() {
+        panic!("Tracing has already been initialized!");
+    }
+
+    log::log("substrate", &format!("Exporting traces to {}", url));
+    tokio::spawn(export_loop(url, service_name, receiver));
+}
+
+/// Export every span finished so far; used on shutdown
+pub async fn shutdown() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let (done, flushed) = oneshot::channel();
+    let flush = async {
+        exporter.send(Export::Flush(done)).await.ok()?;
+        flushed.await.ok()
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, flush).await.is_err() {
+        log::log("substrate", "Timed out exporting the remaining spans");
+    }
+}
+
+/// Collect finished spans into batches and post them to the collector
+async fn export_loop(url: String, service_name: String, mut receiver: mpsc::Receiver<Export>) {
+    let client = hyper::Client::new();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(Export::Span(span)) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Some(Export::Flush(done)) => {
+                    flush(&client, &url, &service_name, &mut batch).await;
+                    let _ = done.send(());
+                    continue;
+                }
+                None => {
+                    flush(&client, &url, &service_name, &mut batch).await;
+                    return;
+                }
+            },
+            _ = ticker.tick() => {}
+        }
+        flush(&client, &url, &service_name, &mut batch).await;
+    }
+}
+
+/// Post a batch of spans to the collector and empty it
+async fn flush(
+    client: &hyper::Client<hyper::client::HttpConnector>,
+    url: &str,
+    service_name: &str,
+    batch: &mut Vec<FinishedSpan>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let body = encode(service_name, batch).to_string();
+    batch.clear();
+
+    let request = hyper::Request::post(url)
+        .header("content-type", "application/json")
+        .body(hyper::Body::from(body));
+    let result = match request {
+        Ok(request) => client.request(request).await.map_err(anyhow::Error::from),
+        Err(e) => Err(anyhow::Error::from(e)),
+    };
+    match result {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => log::log("substrate", &format!("Trace export rejected: {}", response.status())),
+        Err(e) => log::log("substrate", &format!("Trace export failed: {}", e)),
+    }
+}
+
+/// Encode spans as an OTLP/JSON `ExportTraceServiceRequest`
+fn encode(service_name: &str, spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": encode_hex(&span.context.trace_id),
+                "spanId": encode_hex(&span.context.span_id),
+                "name": span.name,
+                "kind": match span.kind {
+                    SpanKind::Internal => 1,
+                    SpanKind::Server => 2,
+                },
+                "startTimeUnixNano": unix_nanos(span.start).to_string(),
+                "endTimeUnixNano": unix_nanos(span.end).to_string(),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                    .collect::<Vec<_>>(),
+                "status": match &span.error {
+                    Some(message) => json!({ "code": 2, "message": message }),
+                    None => json!({ "code": 1 }),
+                },
+            });
+            if let Some(parent) = &span.parent_span_id {
+                value["parentSpanId"] = Value::String(encode_hex(parent));
+            }
+            value
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "substrate", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_traceparent() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(encode_hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(encode_hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+
+        let unsampled = SpanContext::from_traceparent(&TRACEPARENT.replace("-01", "-00")).unwrap();
+        assert!(!unsampled.sampled);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0x",
+        ] {
+            assert_eq!(SpanContext::from_traceparent(value), None, "{}", value);
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use warp::http::{HeaderMap, Method, Version};
+use warp::http::{HeaderMap, Method};
 use bytes::Bytes;
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use crate::auth::Principal;
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)] // Not every field is exposed to guests yet
 pub struct HttpRequest {
     pub method: Method,
     pub headers: HeaderMap,
@@ -10,5 +17,116 @@ pub struct HttpRequest {
     pub path: String,
     pub query: String,
     pub body: Bytes,
+    pub body_stream: Option<BodyStream>, // Rest of the body, for applets that stream it
     pub remote_addr: Option<SocketAddr>,
+    pub request_id: String,
+    pub principal: Option<Principal>, // Verified caller, when the applet requires authentication
+    pub response_sink: Option<ResponseSink>, // Where a streamed response goes, when served over a connection
 }
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// A request body handed to the guest as it arrives
+#[derive(Clone)]
+pub struct BodyStream {
+    chunks: Arc<Mutex<mpsc::Receiver<Result<Bytes, BodyError>>>>,
+    timeout: Option<Duration>, // Longest wait for a chunk, so a trickling client cannot pin the guest
+}
+
+impl BodyStream {
+    pub fn new(chunks: mpsc::Receiver<Result<Bytes, BodyError>>, timeout: Option<Duration>) -> Self {
+        BodyStream { chunks: Arc::new(Mutex::new(chunks)), timeout }
+    }
+
+    /// Wait for the next chunk; `None` once the body is complete. Must be
+    /// called from a thread of the runtime's blocking pool.
+    pub fn next_chunk(&self) -> Result<Option<Bytes>, BodyError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let Some(timeout) = self.timeout else {
+            return chunks.blocking_recv().transpose();
+        };
+        Handle::current()
+            .block_on(tokio::time::timeout(timeout, chunks.recv()))
+            .map_err(|_| BodyError::Failed(format!("no data from the client for {}s", timeout.as_secs())))?
+            .transpose()
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+/// Where a guest's streamed response goes. The status and headers are
+/// handed over once, together with the channel its body frames follow on.
+#[derive(Clone)]
+pub struct ResponseSink {
+    head: Arc<Mutex<Option<oneshot::Sender<StreamedHead>>>>,
+    capacity: usize, // Frames buffered ahead of the client
+    trailers: bool,  // The connection can carry trailers
+}
+
+impl ResponseSink {
+    pub fn new(capacity: usize, trailers: bool) -> (Self, oneshot::Receiver<StreamedHead>) {
+        let (head, started) = oneshot::channel();
+        (ResponseSink { head: Arc::new(Mutex::new(Some(head))), capacity, trailers }, started)
+    }
+
+    /// Whether trailers reach the client; HTTP/1.1 responses end without them
+    pub fn trailers(&self) -> bool {
+        self.trailers
+    }
+
+    /// Hand over the status and headers; `None` when the response was
+    /// already started or nobody is waiting for it any more
+    pub fn start(&self, status_code: u16, headers: HeaderMap) -> Option<mpsc::Sender<ResponseFrame>> {
+        let head = self.head.lock().unwrap().take()?;
+        let (frames, receiver) = mpsc::channel(self.capacity.max(1));
+        head.send(StreamedHead { status_code, headers, frames: receiver }).ok()?;
+        Some(frames)
+    }
+}
+
+impl fmt::Debug for ResponseSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseSink")
+    }
+}
+
+/// The start of a streamed response
+pub struct StreamedHead {
+    pub status_code: u16,
+    pub headers: HeaderMap,
+    pub frames: mpsc::Receiver<ResponseFrame>, // Body chunks, then trailers
+}
+
+/// Part of a streamed response body
+pub enum ResponseFrame {
+    Data(Bytes),
+    Trailers(HeaderMap), // Only sent when the connection can carry them
+    Abort, // The guest failed midway; the client must not see a complete body
+}
+
+/// Why a request body could not be received
+#[derive(Clone, Debug)]
+pub enum BodyError {
+    TooLarge(usize), // Over the applet's limit, in bytes
+    Failed(String),  // The connection failed while reading it
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(limit) => write!(f, "Request body exceeds the {} byte limit", limit),
+            BodyError::Failed(reason) => write!(f, "Failed to read the request body: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+
+use crate::applet_store::AppletSettings;
+use crate::log::{self, Level};
+use crate::runner::Runner;
+use crate::config;
+
+/// How often the directory is scanned for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification time and size, used to notice that a file was rewritten
+type Signature = (SystemTime, u64);
+
+/// A `.wasm` file in the watched directory
+#[derive(Default)]
+struct WatchedFile {
+    uuid: Option<Uuid>,        // Applet stored for the file, once it compiled
+    loaded: Option<Signature>, // Version last loaded (or that last failed to)
+    pending: Option<Signature>, // Version seen on the previous scan, not yet settled
+}
+
+/// Keeps the applets of a directory in sync with its `.wasm` files
+struct Watcher {
+    dir: PathBuf,
+    runner: Arc<Runner>,
+    files: HashMap<PathBuf, WatchedFile>,
+}
+
+/// Load every `.wasm` file in `dir` and keep reloading them as they change.
+/// Each file becomes an applet named after its file stem.
+pub fn start(dir: &Path, runner: Arc<Runner>) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(anyhow!("Watch directory '{}' is not a directory", dir.display()));
+    }
+    log::log("substrate", &format!("Watching {} for applets", dir.display()));
+
+    let mut watcher = Watcher {
+        dir: dir.to_path_buf(),
+        runner,
+        files: HashMap::new(),
+    };
+    watcher.scan(false);
+
+    std::thread::Builder::new()
+        .name("applet-watch".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(POLL_INTERVAL);
+            watcher.scan(true);
+        })?;
+    Ok(())
+}
+
+impl Watcher {
+    /// Load new and changed files. With `settle`, a change is only picked
+    /// up once the file has stayed the same for a whole poll interval, so
+    /// half-written builds are not compiled.
+    fn scan(&mut self, settle: bool) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::log_with(
+                    "substrate",
+                    Level::Error,
+                    &format!("Failed to read watch directory '{}': {}", self.dir.display(), e),
+                    &[],
+                );
+                return;
+            }
+        };
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let Some(signature) = signature(&path) else {
+                continue;
+            };
+            let file = self.files.entry(path.clone()).or_default();
+            if file.loaded == Some(signature) {
+                continue;
+            }
+            if settle && file.pending != Some(signature) {
+                file.pending = Some(signature);
+                continue;
+            }
+            file.pending = None;
+            file.loaded = Some(signature);
+
+            let uuid = file.uuid;
+            if let Some(uuid) = self.load(&path, uuid) {
+                self.files.entry(path).or_default().uuid = Some(uuid);
+            }
+        }
+    }
+
+    /// Store a new applet for the file, or swap in the new version of an
+    /// existing one. Errors are logged and leave the old version serving.
+    fn load(&self, path: &Path, uuid: Option<Uuid>) -> Option<Uuid> {
+        let name = applet_name(path);
+        let result = read_wasm(path).and_then(|wasm_binary| match uuid {
+            Some(uuid) => self.runner.reload(uuid, wasm_binary).map(|()| uuid),
+            None => self.runner.install(wasm_binary, name.clone(), AppletSettings { pinned: true, ..Default::default() }),
+        });
+
+        match (result, uuid) {
+            (Ok(new_uuid), None) => {
+                log::log("substrate", &format!("Applet '{}' stored with UUID: {}", name, new_uuid));
+                Some(new_uuid)
+            }
+            (Ok(uuid), Some(_)) => {
+                log::log("substrate", &format!("Applet '{}' reloaded from {}", name, path.display()));
+                Some(uuid)
+            }
+            (Err(e), None) => {
+                log::log_with(
+                    "substrate",
+                    Level::Error,
+                    &format!("Failed to load applet '{}' from {}: {:#}", name, path.display(), e),
+                    &[],
+                );
+                None
+            }
+            (Err(e), Some(uuid)) => {
+                log::log_with(
+                    "substrate",
+                    Level::Error,
+                    &format!("Failed to reload applet '{}', keeping the previous version: {:#}", name, e),
+                    &[],
+                );
+                Some(uuid)
+            }
+        }
+    }
+}
+
+/// Applet name for a watched file: its file stem
+fn applet_name(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn signature(path: &Path) -> Option<Signature> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Read a wasm file, enforcing the configured size limit
+fn read_wasm(path: &Path) -> Result<Vec<u8>> {
+    let max_applet_bytes = config::global_config().max_applet_bytes;
+    let wasm_binary = std::fs::read(path)
+        .map_err(|e| anyhow!("Failed to read the WASM file '{}': {}", path.display(), e))?;
+    if max_applet_bytes > 0 && wasm_binary.len() > max_applet_bytes {
+        return Err(anyhow!(
+            "WASM file '{}' is {} bytes, above the {} byte limit",
+            path.display(),
+            wasm_binary.len(),
+            max_applet_bytes
+        ));
+    }
+    Ok(wasm_binary)
+}